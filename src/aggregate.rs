@@ -0,0 +1,503 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Output aggregation
+use crate::event::OutputStream;
+use crate::report::{CommandReport, Outcome, RunReport};
+use crate::utils::CmdType;
+use getset::{Getters, Setters};
+use indexmap::IndexMap;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// A rule applied to each line of output before it is compared.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Normalize {
+    /// Replace dates such as `2018-10-26` and times such as `13:45:01.123`
+    /// with `<timestamp>`
+    Timestamps,
+    /// Replace the host name, its configured hostname, and the address it
+    /// connected to, with `<host>`
+    Hostname,
+    /// Trim leading and trailing whitespace
+    Trim,
+    /// Replace every occurrence of the first string with the second
+    Replace(String, String),
+}
+
+/// A group of hosts where the command had the same outcome and produced
+/// identical output.
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+pub struct OutputGroup {
+    /// The outcome of the command on these hosts
+    #[get = "pub"]
+    outcome: Outcome,
+    /// The hosts, in the order they appear in the report
+    #[get = "pub"]
+    hosts: Vec<String>,
+    /// The normalized output
+    #[get = "pub"]
+    lines: Vec<String>,
+}
+
+impl OutputGroup {
+    /// The hosts in compact range form, such as `web[01-20],db1`.
+    #[must_use]
+    pub fn host_range(&self) -> String {
+        host_range(&self.hosts)
+    }
+}
+
+impl fmt::Display for OutputGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = if self.outcome == Outcome::Succeeded {
+            format!("{} ({} hosts)", self.host_range(), self.hosts.len())
+        } else {
+            format!(
+                "{} ({} hosts, {})",
+                self.host_range(),
+                self.hosts.len(),
+                self.outcome
+            )
+        };
+        writeln!(f, "{}", "-".repeat(header.len()))?;
+        writeln!(f, "{}", header)?;
+        write!(f, "{}", "-".repeat(header.len()))?;
+        for line in &self.lines {
+            writeln!(f)?;
+            write!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Groups hosts by identical command output.
+#[derive(Clone, Debug, Getters, Setters)]
+pub struct Aggregator {
+    /// The stream to compare
+    #[get = "pub"]
+    #[set = "pub"]
+    stream: OutputStream,
+    /// The rules applied to each line, in order, before comparing
+    #[get = "pub"]
+    #[set = "pub"]
+    normalize: Vec<Normalize>,
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        Self {
+            stream: OutputStream::Stdout,
+            normalize: Vec::new(),
+        }
+    }
+}
+
+impl Aggregator {
+    /// Group the hosts that planned the given command in the given phase by
+    /// outcome and output.  The groups where the command succeeded come
+    /// first, the largest group first; the groups where it failed, timed out
+    /// or never ran follow, so their output is never mistaken for that of a
+    /// successful run.
+    #[must_use]
+    pub fn aggregate(
+        &self,
+        report: &RunReport,
+        cmd_type: CmdType,
+        cmd_name: &str,
+    ) -> Vec<OutputGroup> {
+        let mut groups: IndexMap<(Outcome, Vec<String>), Vec<String>> = IndexMap::new();
+
+        for (hostname, host_report) in report.hosts() {
+            let command = host_report
+                .commands()
+                .iter()
+                .find(|cmd| *cmd.cmd_type() == cmd_type && cmd.cmd_name() == cmd_name);
+
+            if let Some(command) = command {
+                let addresses = [
+                    host_report.hostname().as_str(),
+                    command
+                        .metrics()
+                        .as_ref()
+                        .map_or("", |metrics| metrics.address().as_str()),
+                ];
+                groups
+                    .entry((
+                        *command.outcome(),
+                        self.lines(hostname, &addresses, command),
+                    ))
+                    .or_default()
+                    .push(hostname.clone());
+            }
+        }
+
+        let mut groups: Vec<OutputGroup> = groups
+            .into_iter()
+            .map(|((outcome, lines), hosts)| OutputGroup {
+                outcome,
+                hosts,
+                lines,
+            })
+            .collect();
+        // A stable sort, so groups of the same size stay in report order
+        groups.sort_by_key(|group| {
+            (
+                group.outcome != Outcome::Succeeded,
+                Reverse(group.hosts.len()),
+            )
+        });
+        groups
+    }
+
+    fn lines(&self, hostname: &str, addresses: &[&str], command: &CommandReport) -> Vec<String> {
+        let lines = match self.stream {
+            OutputStream::Stdout => command.stdout(),
+            OutputStream::Stderr => command.stderr(),
+        };
+
+        lines
+            .iter()
+            .map(|line| {
+                self.normalize
+                    .iter()
+                    .fold(line.clone(), |line, rule| match rule {
+                        Normalize::Timestamps => strip_timestamps(&line),
+                        // The addresses first, as the name is often a prefix
+                        Normalize::Hostname => addresses
+                            .iter()
+                            .filter(|address| !address.is_empty())
+                            .fold(line, |line, address| line.replace(address, "<host>"))
+                            .replace(hostname, "<host>"),
+                        Normalize::Trim => line.trim().to_string(),
+                        Normalize::Replace(from, to) if !from.is_empty() => line.replace(from, to),
+                        Normalize::Replace(..) => line,
+                    })
+            })
+            .collect()
+    }
+}
+
+/// Replace dates (`dddd-dd-dd`) and times (`dd:dd:dd`, with optional
+/// fractional seconds) with `<timestamp>`.
+fn strip_timestamps(line: &str) -> String {
+    const PATTERNS: &[&str] = &["dddd-dd-dd", "dd:dd:dd"];
+    let chars: Vec<char> = line.chars().collect();
+    let mut stripped = String::with_capacity(line.len());
+    let mut idx = 0;
+
+    'outer: while idx < chars.len() {
+        let boundary = idx == 0 || !chars[idx - 1].is_ascii_digit();
+        if boundary {
+            for pattern in PATTERNS {
+                if let Some(mut end) = match_pattern(&chars[idx..], pattern) {
+                    end += idx;
+                    if chars.get(end) == Some(&'.') {
+                        let digits = chars[end + 1..]
+                            .iter()
+                            .take_while(|ch| ch.is_ascii_digit())
+                            .count();
+                        if digits > 0 {
+                            end += 1 + digits;
+                        }
+                    }
+                    if !chars.get(end).map_or(false, char::is_ascii_digit) {
+                        stripped.push_str("<timestamp>");
+                        idx = end;
+                        continue 'outer;
+                    }
+                }
+            }
+        }
+        stripped.push(chars[idx]);
+        idx += 1;
+    }
+    stripped
+}
+
+/// The length of the match if `chars` starts with `pattern`, where `d`
+/// matches any ASCII digit.
+fn match_pattern(chars: &[char], pattern: &str) -> Option<usize> {
+    let mut len = 0;
+    for expected in pattern.chars() {
+        let actual = chars.get(len)?;
+        let matched = if expected == 'd' {
+            actual.is_ascii_digit()
+        } else {
+            *actual == expected
+        };
+        if !matched {
+            return None;
+        }
+        len += 1;
+    }
+    Some(len)
+}
+
+/// Render host names compactly, collapsing names that share a prefix and
+/// differ only in a trailing number into ranges, such as `web[01-03,07]`.
+///
+/// Numbers are only collapsed together when they have the same width, so
+/// zero padding is kept.
+#[must_use]
+pub fn host_range<S: AsRef<str>>(hosts: &[S]) -> String {
+    // (prefix, width) to numbers, in the order each prefix first appears
+    let mut numbered: IndexMap<(&str, usize), BTreeMap<u64, &str>> = IndexMap::new();
+    let mut rendered: IndexMap<(&str, usize), Option<&str>> = IndexMap::new();
+
+    for host in hosts {
+        let host = host.as_ref();
+        let digits = host.chars().rev().take_while(char::is_ascii_digit).count();
+        let (prefix, number) = host.split_at(host.len() - digits);
+
+        match number.parse::<u64>() {
+            Ok(value) if digits > 0 => {
+                let key = (prefix, digits);
+                let _ = numbered.entry(key).or_default().insert(value, number);
+                let _ = rendered.entry(key).or_insert(None);
+            }
+            _ => {
+                let _ = rendered.entry((host, 0)).or_insert(Some(host));
+            }
+        }
+    }
+
+    rendered
+        .into_iter()
+        .map(|(key, plain)| match plain {
+            Some(host) => host.to_string(),
+            None => render_numbers(key.0, &numbered[&key]),
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn render_numbers(prefix: &str, numbers: &BTreeMap<u64, &str>) -> String {
+    if numbers.len() == 1 {
+        if let Some(number) = numbers.values().next() {
+            return format!("{}{}", prefix, number);
+        }
+    }
+
+    let mut ranges: Vec<String> = Vec::new();
+    let mut iter = numbers.iter().peekable();
+    while let Some((start, start_str)) = iter.next() {
+        let mut end = (*start, *start_str);
+        while let Some((next, next_str)) = iter.peek() {
+            if **next == end.0 + 1 {
+                end = (**next, *next_str);
+                let _ = iter.next();
+            } else {
+                break;
+            }
+        }
+
+        if end.0 == *start {
+            ranges.push((*start_str).to_string());
+        } else {
+            ranges.push(format!("{}-{}", start_str, end.1));
+        }
+    }
+    format!("{}[{}]", prefix, ranges.join(","))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{host_range, strip_timestamps, Aggregator, Normalize};
+    use crate::config::Command;
+    use crate::config::Host;
+    use crate::error::MusshResult;
+    use crate::event::{Event, OutputStream};
+    use crate::report::{Outcome, RunReport};
+    use crate::ssh::Metrics;
+    use crate::utils::CmdType;
+    use indexmap::{IndexMap, IndexSet};
+    use serde_json::json;
+
+    #[test]
+    fn host_ranges() {
+        assert_eq!(host_range(&["web01", "web02", "web03"]), "web[01-03]");
+        assert_eq!(
+            host_range(&["web01", "web03", "web02", "web07", "db1", "web9"]),
+            "web[01-03,07],db1,web9"
+        );
+        assert_eq!(host_range(&["m1", "m2", "m4", "m5", "m6"]), "m[1-2,4-6]");
+        assert_eq!(host_range(&["localhost", "web01"]), "localhost,web01");
+        assert_eq!(host_range::<&str>(&[]), "");
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(
+            strip_timestamps("up since 2018-10-26 13:45:01.123, load 0.5"),
+            "up since <timestamp> <timestamp>, load 0.5"
+        );
+        assert_eq!(
+            strip_timestamps("2018-10-26T13:45:01Z"),
+            "<timestamp>T<timestamp>Z"
+        );
+        assert_eq!(strip_timestamps("v12018-10-261"), "v12018-10-261");
+        assert_eq!(strip_timestamps("12:34"), "12:34");
+    }
+
+    fn report(outputs: &[(&str, &str)]) -> RunReport {
+        report_with(outputs, &[])
+    }
+
+    /// A report of `uname` in both phases, with the given stdout in the
+    /// command phase and nothing in the sync phase.  It fails on the failed
+    /// hosts and succeeds elsewhere.
+    fn report_with(outputs: &[(&str, &str)], failed: &[&str]) -> RunReport {
+        let mut hosts_map = IndexMap::new();
+        for (hostname, _) in outputs {
+            let mut host = Host::default();
+            let _ = host.set_hostname(format!("{}.example.com", hostname));
+            let mut cmds = IndexMap::new();
+            let _ = cmds.insert("uname".to_string(), Command::default());
+            let mut cmd_map = IndexMap::new();
+            let _ = cmd_map.insert(CmdType::Cmd, cmds.clone());
+            let _ = cmd_map.insert(CmdType::SyncCmd, cmds);
+            let _ = hosts_map.insert(hostname.to_string(), (host, cmd_map));
+        }
+
+        let mut report = RunReport::new(&IndexSet::new(), &hosts_map);
+        for (hostname, line) in outputs {
+            report.record(&Event::OutputLine {
+                host: hostname.to_string(),
+                cmd_name: "uname".to_string(),
+                cmd_type: CmdType::Cmd,
+                stream: OutputStream::Stdout,
+                line: line.to_string(),
+                timestamp: 0,
+            });
+            for cmd_type in &[CmdType::Cmd, CmdType::SyncCmd] {
+                report.record(&Event::CommandFinished {
+                    host: hostname.to_string(),
+                    cmd_name: "uname".to_string(),
+                    cmd_type: *cmd_type,
+                    result: if failed.contains(hostname) {
+                        Err("failed".into())
+                    } else {
+                        Ok(Metrics::default())
+                    },
+                });
+            }
+        }
+        report
+    }
+
+    #[test]
+    fn groups_identical_output() {
+        let report = report(&[
+            ("web01", "Linux 4.19"),
+            ("db1", "Linux 4.14"),
+            ("web02", "Linux 4.19"),
+            ("web03", "Linux 4.19"),
+        ]);
+        let groups = Aggregator::default().aggregate(&report, CmdType::Cmd, "uname");
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].host_range(), "web[01-03]");
+        assert_eq!(*groups[0].lines(), vec!["Linux 4.19"]);
+        assert_eq!(groups[1].host_range(), "db1");
+        assert_eq!(
+            groups[0].to_string(),
+            "--------------------\nweb[01-03] (3 hosts)\n--------------------\nLinux 4.19"
+        );
+        assert!(Aggregator::default()
+            .aggregate(&report, CmdType::Cmd, "ls")
+            .is_empty());
+    }
+
+    #[test]
+    fn normalizes_before_grouping() {
+        let report = report(&[
+            ("web01", " web01 up 2018-10-26 "),
+            ("web02", "web02 up 2018-10-27"),
+        ]);
+        let mut aggregator = Aggregator::default();
+        let _ = aggregator.set_normalize(vec![
+            Normalize::Trim,
+            Normalize::Hostname,
+            Normalize::Timestamps,
+            Normalize::Replace("up".to_string(), "UP".to_string()),
+        ]);
+        let groups = aggregator.aggregate(&report, CmdType::Cmd, "uname");
+        assert_eq!(groups.len(), 1);
+        assert_eq!(*groups[0].lines(), vec!["<host> UP <timestamp>"]);
+    }
+
+    #[test]
+    fn groups_by_outcome() {
+        let report = report_with(
+            &[("m1", ""), ("m2", ""), ("m3", ""), ("m4", "")],
+            &["m2", "m3"],
+        );
+        let groups = Aggregator::default().aggregate(&report, CmdType::Cmd, "uname");
+        assert_eq!(groups.len(), 2);
+        assert_eq!(*groups[0].outcome(), Outcome::Succeeded);
+        assert_eq!(*groups[0].hosts(), vec!["m1", "m4"]);
+        assert_eq!(*groups[1].outcome(), Outcome::Failed);
+        assert_eq!(*groups[1].hosts(), vec!["m2", "m3"]);
+        assert_eq!(
+            groups[1].to_string(),
+            "------------------------\nm[2-3] (2 hosts, failed)\n------------------------\n"
+        );
+    }
+
+    #[test]
+    fn separates_phases() {
+        let report = report(&[("m1", "Linux"), ("m2", "Linux")]);
+        let aggregator = Aggregator::default();
+        let groups = aggregator.aggregate(&report, CmdType::Cmd, "uname");
+        assert_eq!(groups.len(), 1);
+        assert_eq!(*groups[0].lines(), vec!["Linux"]);
+        let groups = aggregator.aggregate(&report, CmdType::SyncCmd, "uname");
+        assert_eq!(groups.len(), 1);
+        assert!(groups[0].lines().is_empty());
+    }
+
+    #[test]
+    fn normalizes_hostname_without_metrics() {
+        let report = report_with(
+            &[("m1", "m1.example.com down"), ("m2", "m2.example.com down")],
+            &["m1", "m2"],
+        );
+        let mut aggregator = Aggregator::default();
+        let _ = aggregator.set_normalize(vec![Normalize::Hostname]);
+        let groups = aggregator.aggregate(&report, CmdType::Cmd, "uname");
+        assert_eq!(groups.len(), 1);
+        assert_eq!(*groups[0].lines(), vec!["<host> down"]);
+    }
+
+    #[test]
+    fn normalizes_failover_address() -> MusshResult<()> {
+        let mut report = report(&[("m1", "10.0.0.2 up"), ("m2", "m2.example.com up")]);
+        // m1 was reached through its failover address
+        let metrics: Metrics = serde_json::from_value(json!({
+            "hostname": "m1.example.com",
+            "address": "10.0.0.2",
+            "cmd_name": "uname",
+            "duration": { "secs": 0, "nanos": 0 },
+            "timestamp": 0,
+            "attempts": 1,
+        }))?;
+        report.record(&Event::CommandFinished {
+            host: "m1".to_string(),
+            cmd_name: "uname".to_string(),
+            cmd_type: CmdType::Cmd,
+            result: Ok(metrics),
+        });
+        let mut aggregator = Aggregator::default();
+        let _ = aggregator.set_normalize(vec![Normalize::Hostname]);
+        let groups = aggregator.aggregate(&report, CmdType::Cmd, "uname");
+        assert_eq!(groups.len(), 1);
+        assert_eq!(*groups[0].lines(), vec!["<host> up"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,235 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Authentication
+use crate::config::{AuthMethod, Host};
+use crate::error::{MusshErrKind, MusshResult};
+use getset::Getters;
+use ssh2::{KeyboardInteractivePrompt, Prompt, Session};
+use std::env;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Supplies secrets to `Multiplex` while authenticating to a host.
+///
+/// Every method has a default implementation that supplies nothing, so an
+/// implementor only needs to provide the secrets it actually has.
+pub trait AuthProvider: fmt::Debug + Send + Sync {
+    /// The password for `username` on `hostname`.
+    fn password(&self, _hostname: &str, _username: &str) -> Option<String> {
+        None
+    }
+
+    /// The passphrase for the private key at `key`, used to log into `hostname`.
+    fn passphrase(&self, _hostname: &str, _key: &Path) -> Option<String> {
+        None
+    }
+
+    /// The password `username` uses to become `user` on `hostname` when a
+    /// command runs as another user.
+    fn become_password(&self, _hostname: &str, _username: &str, _user: &str) -> Option<String> {
+        None
+    }
+
+    /// The answers to a keyboard-interactive challenge from `hostname`, one
+    /// per prompt.
+    fn keyboard_interactive(
+        &self,
+        _hostname: &str,
+        _username: &str,
+        _instructions: &str,
+        _prompts: &[AuthPrompt],
+    ) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// A keyboard-interactive prompt sent by the server.
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+pub struct AuthPrompt {
+    /// The text to show when prompting.
+    #[get = "pub"]
+    text: String,
+    /// Should the answer be echoed as it is typed?
+    #[get = "pub"]
+    echo: bool,
+}
+
+/// A failed authentication attempt.
+#[derive(Clone, Debug, Eq, PartialEq)]
+crate struct AuthFailure {
+    crate method: AuthMethod,
+    crate reason: String,
+}
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.method, self.reason)
+    }
+}
+
+struct Prompter<'a> {
+    provider: &'a dyn AuthProvider,
+    hostname: &'a str,
+}
+
+impl KeyboardInteractivePrompt for Prompter<'_> {
+    fn prompt(
+        &mut self,
+        username: &str,
+        instructions: &str,
+        prompts: &[Prompt<'_>],
+    ) -> Vec<String> {
+        let prompts: Vec<AuthPrompt> = prompts
+            .iter()
+            .map(|prompt| AuthPrompt {
+                text: prompt.text.to_string(),
+                echo: prompt.echo,
+            })
+            .collect();
+        self.provider
+            .keyboard_interactive(self.hostname, username, instructions, &prompts)
+    }
+}
+
+/// Try each of the host's authentication methods in order, returning the
+/// method that succeeded.  If every method fails, the error lists each
+/// attempt along with the reason it failed.
+crate fn authenticate(
+    sess: &Session,
+    host: &Host,
+    provider: &Option<Arc<dyn AuthProvider>>,
+) -> MusshResult<AuthMethod> {
+    let mut failures = Vec::new();
+
+    for method in host.auth_methods() {
+        for attempt in try_method(sess, host, provider, method) {
+            match attempt {
+                Ok(()) if sess.authenticated() => return Ok(method),
+                Ok(()) => failures.push(AuthFailure {
+                    method,
+                    reason: "not authenticated".to_string(),
+                }),
+                Err(reason) => failures.push(AuthFailure { method, reason }),
+            }
+        }
+    }
+
+    let target = format!("{}@{}", host.username(), host.hostname());
+    Err(MusshErrKind::SshAuthentication(target, failures).into())
+}
+
+/// Try each of the host's identity files in order, stopping at the first
+/// one that is accepted.
+fn try_identities(
+    sess: &Session,
+    host: &Host,
+    provider: &Option<Arc<dyn AuthProvider>>,
+) -> Vec<Result<(), String>> {
+    let identities = host.identities();
+
+    if identities.is_empty() {
+        return vec![Err("no identity configured".to_string())];
+    }
+
+    let mut attempts = Vec::new();
+    for key in identities {
+        let attempt = try_identity(sess, host, provider, &key)
+            .map_err(|e| format!("{}: {}", key.display(), e));
+        let accepted = attempt.is_ok();
+        attempts.push(attempt);
+
+        if accepted {
+            break;
+        }
+    }
+    attempts
+}
+
+fn try_identity(
+    sess: &Session,
+    host: &Host,
+    provider: &Option<Arc<dyn AuthProvider>>,
+    key: &Path,
+) -> Result<(), String> {
+    let passphrase = provider
+        .as_ref()
+        .and_then(|provider| provider.passphrase(host.hostname(), key))
+        .or_else(|| {
+            host.passphrase_env()
+                .as_ref()
+                .and_then(|var| env::var(var).ok())
+        });
+    let pubkey = host.public_key(key).or_else(|| public_key(key));
+
+    sess.userauth_pubkey_file(
+        host.username(),
+        pubkey.as_deref(),
+        key,
+        passphrase.as_deref(),
+    )
+    .map_err(|e| e.to_string())
+}
+
+/// The public half of the given private key, if it sits alongside it with a
+/// `.pub` extension.  Used when the host configures no public key for it.
+fn public_key(key: &Path) -> Option<PathBuf> {
+    let mut pubkey = key.as_os_str().to_owned();
+    pubkey.push(".pub");
+    let pubkey = PathBuf::from(pubkey);
+
+    if pubkey.is_file() {
+        Some(pubkey)
+    } else {
+        None
+    }
+}
+
+fn try_method(
+    sess: &Session,
+    host: &Host,
+    provider: &Option<Arc<dyn AuthProvider>>,
+    method: AuthMethod,
+) -> Vec<Result<(), String>> {
+    match method {
+        AuthMethod::Agent => vec![sess
+            .userauth_agent(host.username())
+            .map_err(|e| e.to_string())],
+        AuthMethod::PublicKey => try_identities(sess, host, provider),
+        AuthMethod::Password => vec![try_password(sess, host, provider)],
+        AuthMethod::KeyboardInteractive => vec![try_keyboard_interactive(sess, host, provider)],
+    }
+}
+
+fn try_password(
+    sess: &Session,
+    host: &Host,
+    provider: &Option<Arc<dyn AuthProvider>>,
+) -> Result<(), String> {
+    let password = provider
+        .as_ref()
+        .and_then(|provider| provider.password(host.hostname(), host.username()))
+        .ok_or("no password supplied")?;
+    sess.userauth_password(host.username(), &password)
+        .map_err(|e| e.to_string())
+}
+
+fn try_keyboard_interactive(
+    sess: &Session,
+    host: &Host,
+    provider: &Option<Arc<dyn AuthProvider>>,
+) -> Result<(), String> {
+    let provider = provider.as_ref().ok_or("no auth provider configured")?;
+    let mut prompter = Prompter {
+        provider: provider.as_ref(),
+        hostname: host.hostname(),
+    };
+    sess.userauth_keyboard_interactive(host.username(), &mut prompter)
+        .map_err(|e| e.to_string())
+}
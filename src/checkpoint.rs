@@ -0,0 +1,343 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Resumable runs
+use crate::error::{MusshErrKind, MusshResult};
+use crate::event::{Event, Observer};
+use crate::report::Outcome;
+use crate::utils::{fnv1a, CmdType, MultiplexMapType};
+use chrono::Utc;
+use indexmap::{IndexMap, IndexSet};
+use serde_derive::{Deserialize, Serialize};
+use slog::{error, Logger};
+use slog_try::try_error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// The first line of a checkpoint journal.
+#[derive(Debug, Deserialize, Serialize)]
+struct Header {
+    fingerprint: String,
+}
+
+/// A finished command, as recorded in a checkpoint journal.
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+    host: String,
+    cmd_name: String,
+    cmd_type: CmdType,
+    outcome: Outcome,
+    timestamp: i64,
+}
+
+/// A journal of every command that finishes during a run, so that a run
+/// that is killed part way through can be resumed.
+///
+/// Add it to a `Multiplex` as an observer.  The journal starts with a
+/// fingerprint of the plan, and a resume is refused if the plan has changed.
+#[derive(Debug)]
+pub struct Checkpoint {
+    /// The path to the journal
+    path: PathBuf,
+    /// The journal, opened for appending
+    file: Mutex<File>,
+    /// The (host, command) pairs that have already succeeded
+    succeeded: IndexSet<(String, CmdType, String)>,
+    /// Logs the entries that could not be recorded
+    stderr: Option<Logger>,
+}
+
+impl Checkpoint {
+    /// Start a new journal at the given path for the given plan, replacing
+    /// any journal already there.
+    ///
+    /// # Errors
+    /// * The journal could not be written.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        sync_hosts: &IndexSet<String>,
+        hosts_map: &MultiplexMapType,
+    ) -> MusshResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = File::create(&path)?;
+        let header = Header {
+            fingerprint: fingerprint(sync_hosts, hosts_map)?,
+        };
+        let mut line = serde_json::to_vec(&header)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            succeeded: IndexSet::new(),
+            stderr: None,
+        })
+    }
+
+    /// Resume the journal at the given path for the same plan, or start a
+    /// new one if there is none.  Use `pending` to remove the commands that
+    /// have already succeeded from the plan.
+    ///
+    /// # Errors
+    /// * The journal could not be read.
+    /// * The journal was written for a different plan.
+    pub fn resume<P: AsRef<Path>>(
+        path: P,
+        sync_hosts: &IndexSet<String>,
+        hosts_map: &MultiplexMapType,
+    ) -> MusshResult<Self> {
+        let path = path.as_ref();
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => {
+                return Self::create(path, sync_hosts, hosts_map)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut lines = BufReader::new(file).lines();
+        let header: Header = match lines.next() {
+            Some(line) => serde_json::from_str(&line?)?,
+            None => return Self::create(path, sync_hosts, hosts_map),
+        };
+        if header.fingerprint != fingerprint(sync_hosts, hosts_map)? {
+            return Err(MusshErrKind::CheckpointMismatch(path.display().to_string()).into());
+        }
+
+        let mut succeeded = IndexSet::new();
+        for line in lines {
+            // A line cut short when the run was killed is skipped.
+            if let Ok(entry) = serde_json::from_str::<Entry>(&line?) {
+                let key = (entry.host, entry.cmd_type, entry.cmd_name);
+                if entry.outcome == Outcome::Succeeded {
+                    let _ = succeeded.insert(key);
+                } else {
+                    let _ = succeeded.shift_remove(&key);
+                }
+            }
+        }
+
+        let mut file = OpenOptions::new().read(true).append(true).open(path)?;
+        // Terminate a line cut short, so the next entry starts on its own.
+        let mut last = [0; 1];
+        let _ = file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            file.write_all(b"\n")?;
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+            succeeded,
+            stderr: None,
+        })
+    }
+
+    /// The path to the journal.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Set the logger for the entries that could not be recorded while
+    /// observing a run.  A command missing from the journal runs again on
+    /// resume.
+    pub fn set_stderr(&mut self, stderr: Option<Logger>) -> &mut Self {
+        self.stderr = stderr;
+        self
+    }
+
+    /// The plan, without the commands that already succeeded.  Hosts with
+    /// nothing left to run are removed.
+    #[must_use]
+    pub fn pending(&self, hosts_map: &MultiplexMapType) -> MultiplexMapType {
+        hosts_map
+            .iter()
+            .filter_map(|(hostname, (host, cmd_map))| {
+                let cmd_map: IndexMap<_, _> = cmd_map
+                    .iter()
+                    .map(|(cmd_type, cmds)| {
+                        let cmds = cmds
+                            .iter()
+                            .filter(|(cmd_name, _)| {
+                                !self.succeeded.contains(&(
+                                    hostname.clone(),
+                                    *cmd_type,
+                                    (*cmd_name).clone(),
+                                ))
+                            })
+                            .map(|(cmd_name, cmd)| (cmd_name.clone(), cmd.clone()))
+                            .collect::<IndexMap<_, _>>();
+                        (*cmd_type, cmds)
+                    })
+                    .collect();
+
+                if cmd_map.values().all(IndexMap::is_empty) {
+                    None
+                } else {
+                    Some((hostname.clone(), (host.clone(), cmd_map)))
+                }
+            })
+            .collect()
+    }
+
+    fn append(&self, entry: &Entry) -> MusshResult<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().map_err(|_| "checkpoint lock poisoned")?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+impl Observer for Checkpoint {
+    fn on_event(&self, event: &Event) {
+        if let Event::CommandFinished {
+            host,
+            cmd_name,
+            cmd_type,
+            result,
+        } = event
+        {
+            let entry = Entry {
+                host: host.clone(),
+                cmd_name: cmd_name.clone(),
+                cmd_type: *cmd_type,
+                outcome: Outcome::of(result),
+                timestamp: Utc::now().timestamp_millis(),
+            };
+            if let Err(e) = self.append(&entry) {
+                try_error!(
+                    self.stderr,
+                    "checkpoint";
+                    "path" => self.path.display().to_string(),
+                    "host" => host,
+                    "cmd" => cmd_name,
+                    "error" => e.to_string()
+                );
+            }
+        }
+    }
+}
+
+/// A fingerprint of everything that determines what a run does.
+fn fingerprint(sync_hosts: &IndexSet<String>, hosts_map: &MultiplexMapType) -> MusshResult<String> {
+    let plan = serde_json::to_vec(&(sync_hosts, hosts_map))?;
+    Ok(format!("{:016x}", fnv1a(&plan)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::Checkpoint;
+    use crate::config::{Command, Host};
+    use crate::error::MusshResult;
+    use crate::ssh::Multiplex;
+    use crate::utils::{CmdType, MultiplexMapType};
+    use indexmap::{IndexMap, IndexSet};
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn journal(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("mussh-{}-{}.ndjson", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn hosts_map(fail: &str) -> MultiplexMapType {
+        let mut host = Host::default();
+        let _ = host.set_hostname("localhost".to_string());
+        let mut hosts_map = IndexMap::new();
+        for (hostname, cmd) in &[("m1", "true"), ("m2", fail)] {
+            let mut cmds = IndexMap::new();
+            let mut command = Command::default();
+            let _ = command.set_command(cmd.to_string());
+            let _ = cmds.insert("check".to_string(), command);
+            let mut cmd_map = IndexMap::new();
+            let _ = cmd_map.insert(CmdType::Cmd, cmds);
+            let _ = cmd_map.insert(CmdType::SyncCmd, IndexMap::new());
+            let _ = hosts_map.insert(hostname.to_string(), (host.clone(), cmd_map));
+        }
+        hosts_map
+    }
+
+    #[test]
+    fn resume_skips_succeeded() -> MusshResult<()> {
+        let path = journal("resume");
+        let sync_hosts = IndexSet::new();
+        let plan = hosts_map("false");
+
+        let checkpoint = Arc::new(Checkpoint::create(&path, &sync_hosts, &plan)?);
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.add_observer(checkpoint);
+        let _ = multiplex.multiplex(&sync_hosts, plan.clone());
+
+        // Simulate the run being killed part way through writing a line
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(b"{\"host\":\"m2\",\"cmd_na")?;
+
+        let checkpoint = Checkpoint::resume(&path, &sync_hosts, &plan)?;
+        let pending = checkpoint.pending(&plan);
+        let hostnames: Vec<&String> = pending.keys().collect();
+        assert_eq!(hostnames, vec!["m2"]);
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn resume_refuses_changed_plan() -> MusshResult<()> {
+        let path = journal("changed");
+        let sync_hosts = IndexSet::new();
+        let _ = Checkpoint::create(&path, &sync_hosts, &hosts_map("false"))?;
+
+        assert!(Checkpoint::resume(&path, &sync_hosts, &hosts_map("false")).is_ok());
+        assert!(Checkpoint::resume(&path, &sync_hosts, &hosts_map("exit 2")).is_err());
+        let sync_hosts: IndexSet<String> = vec!["m1".to_string()].into_iter().collect();
+        assert!(Checkpoint::resume(&path, &sync_hosts, &hosts_map("false")).is_err());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn resume_without_journal() -> MusshResult<()> {
+        let path = journal("missing");
+        let plan = hosts_map("false");
+        let checkpoint = Checkpoint::resume(&path, &IndexSet::new(), &plan)?;
+        assert_eq!(checkpoint.pending(&plan), plan);
+        assert!(path.exists());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn resume_keeps_whole_lines() -> MusshResult<()> {
+        let path = journal("lines");
+        let sync_hosts = IndexSet::new();
+        let plan = hosts_map("false");
+        let _ = Checkpoint::create(&path, &sync_hosts, &plan)?;
+
+        for _ in 0..3 {
+            let _ = Checkpoint::resume(&path, &sync_hosts, &plan)?;
+        }
+        let journal = fs::read_to_string(&path)?;
+        assert!(journal.ends_with('\n'));
+        assert!(!journal.contains("\n\n"));
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
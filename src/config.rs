@@ -7,17 +7,22 @@
 // modified, or distributed except according to those terms.
 
 //! Configuration
-use crate::error::{MusshErr, MusshResult};
+use crate::error::{MusshErr, MusshErrKind, MusshResult};
+use crate::report::{Outcome, RunReport};
 use crate::utils::{self, CmdType, MultiplexMapType};
 use clap::ArgMatches;
 use getset::{Getters, Setters};
 use indexmap::{IndexMap, IndexSet};
 use serde_derive::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, Read};
-use std::path::PathBuf;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// The runtime configuration for mussh
 #[derive(Clone, Debug, Default, Eq, Getters, PartialEq, Setters)]
@@ -108,10 +113,27 @@ impl Mussh {
         utils::as_set(hosts.iter().flat_map(|host| self.hostnames(host)))
     }
 
-    fn host_tuple(&self, hostname: &str) -> Option<(String, Host)> {
-        self.hosts()
-            .get(hostname)
-            .map(|host| (hostname.to_string(), host.clone()))
+    /// The host, with the environment of each of the given hostlists it is
+    /// in, later lists over earlier ones, under its own.  Hostlists that
+    /// were not asked for are left out, so the same host picks up the same
+    /// environment however the hostlists are named.
+    fn host_tuple(&self, hostname: &str, hostlists: &IndexSet<String>) -> Option<(String, Host)> {
+        self.hosts().get(hostname).map(|host| {
+            let mut env: BTreeMap<String, String> = hostlists
+                .iter()
+                .filter_map(|name| self.hostlist().get(name))
+                .filter(|hosts| hosts.hostnames().iter().any(|name| name == hostname))
+                .filter_map(|hosts| hosts.env().clone())
+                .flatten()
+                .collect();
+            env.extend(host.env().clone().unwrap_or_default());
+
+            let mut host = host.clone();
+            if !env.is_empty() {
+                host.env = Some(env);
+            }
+            (hostname.to_string(), host)
+        })
     }
 
     fn cmd_tuple(&self, cmd_name: &str) -> Option<(String, Command)> {
@@ -127,7 +149,7 @@ impl Mussh {
         let configured = self.configured_hostlists();
         expanded
             .intersection(&configured)
-            .filter_map(|hostname| self.host_tuple(hostname))
+            .filter_map(|hostname| self.host_tuple(hostname, hosts))
             .collect()
     }
 
@@ -144,31 +166,46 @@ impl Mussh {
         &self,
         target_host: &Host,
         expected_cmds: &IndexMap<String, Command>,
-    ) -> IndexMap<String, String> {
+    ) -> IndexMap<String, Command> {
         expected_cmds
             .iter()
             .map(|(cmd_name, command)| self.cmd_map_tuple(command, cmd_name, target_host))
             .collect()
     }
 
-    fn cmd_map_tuple(&self, command: &Command, cmd_name: &str, host: &Host) -> (String, String) {
-        (
-            cmd_name.to_string(),
-            if let Some(alias_vec) = host.alias() {
-                let mut cmd = command.command().clone();
-                for alias in alias_vec {
-                    if alias.aliasfor() == cmd_name {
-                        if let Some(int_command) = self.cmd().get(alias.command()) {
-                            cmd = int_command.command().clone();
-                            break;
-                        }
+    fn cmd_map_tuple(&self, command: &Command, cmd_name: &str, host: &Host) -> (String, Command) {
+        let cmd = if let Some(alias_vec) = host.alias() {
+            let mut cmd = command.clone();
+            for alias in alias_vec {
+                if alias.aliasfor() == cmd_name {
+                    if let Some(int_command) = self.cmd().get(alias.command()) {
+                        cmd = int_command.clone();
+                        break;
                     }
                 }
-                cmd
-            } else {
-                command.command().clone()
-            },
-        )
+            }
+            cmd
+        } else {
+            command.clone()
+        };
+        (cmd_name.to_string(), with_host_env(cmd, host))
+    }
+
+    /// Check that every command sets exactly one of `command`, `upload`,
+    /// `fetch`, `sync` or `script`.  Configuration read from a file is
+    /// checked when it is read.
+    ///
+    /// # Errors
+    /// * A command sets none of them, or more than one.
+    pub fn validate(&self) -> MusshResult<()> {
+        match self
+            .cmd()
+            .iter()
+            .find(|(_, command)| command.actions() != 1)
+        {
+            Some((cmd_name, _)) => Err(MusshErrKind::InvalidCommand(cmd_name.clone()).into()),
+            None => Ok(()),
+        }
     }
 
     /// Create a host map suitable for use with multiples from this config, and
@@ -185,7 +222,7 @@ impl Mussh {
         for (hostname, host) in &actual_hosts {
             let cmd_tuple = hosts_map.entry(hostname.clone()).or_insert((
                 host.clone(),
-                IndexMap::<CmdType, IndexMap<String, String>>::new(),
+                IndexMap::<CmdType, IndexMap<String, Command>>::new(),
             ));
             let cmds = self.actual_cmd_map(host, &actual_cmds);
             let sync_cmds = self.actual_cmd_map(host, &actual_sync_cmds);
@@ -205,6 +242,50 @@ impl Mussh {
 
         hosts_map
     }
+
+    /// Create a host map that reruns only the commands that did not succeed
+    /// in a previous run, on the hosts where they did not succeed.  Commands
+    /// keep the phase they ran in, and the returned sync hosts are the rerun
+    /// hosts that were sync hosts before, so both can be passed to
+    /// `Multiplex::run`.  Hosts take their environment from the hostlists
+    /// the previous run named, so pass the `HostsCmds` it was planned from.
+    #[must_use]
+    pub fn rerun_host_map(
+        &self,
+        host_cmds: &HostsCmds,
+        report: &RunReport,
+    ) -> (IndexSet<String>, MultiplexMapType) {
+        let planned = self.to_host_map(host_cmds);
+        let mut sync_hosts = IndexSet::new();
+        let mut hosts_map = IndexMap::new();
+
+        for (hostname, host_report) in report.hosts() {
+            if host_report.is_success() {
+                continue;
+            }
+
+            if let Some((host, _)) = planned.get(hostname).cloned() {
+                let mut cmd_map = IndexMap::new();
+                for cmd_type in &[CmdType::Cmd, CmdType::SyncCmd] {
+                    let cmds = host_report
+                        .commands()
+                        .iter()
+                        .filter(|cmd| cmd.cmd_type() == cmd_type)
+                        .filter(|cmd| *cmd.outcome() != Outcome::Succeeded)
+                        .filter_map(|cmd| self.cmd_tuple(cmd.cmd_name()))
+                        .map(|(cmd_name, command)| self.cmd_map_tuple(&command, &cmd_name, &host))
+                        .collect();
+                    let _ = cmd_map.insert(*cmd_type, cmds);
+                }
+                if *host_report.sync_host() {
+                    let _ = sync_hosts.insert(hostname.clone());
+                }
+                let _ = hosts_map.insert(hostname.clone(), (host, cmd_map));
+            }
+        }
+
+        (sync_hosts, hosts_map)
+    }
 }
 
 impl TryFrom<PathBuf> for Mussh {
@@ -214,7 +295,9 @@ impl TryFrom<PathBuf> for Mussh {
         let mut buf_reader = BufReader::new(File::open(path)?);
         let mut buffer = String::new();
         let _bytes_read = buf_reader.read_to_string(&mut buffer)?;
-        Ok(toml::from_str(&buffer)?)
+        let config: Self = toml::from_str(&buffer)?;
+        config.validate()?;
+        Ok(config)
     }
 }
 
@@ -222,6 +305,16 @@ fn requested(commands: &IndexSet<String>) -> IndexSet<String> {
     utils::as_set(commands.iter().cloned())
 }
 
+/// The command, with the environment of its host under its own.
+fn with_host_env(mut cmd: Command, host: &Host) -> Command {
+    if let Some(host_env) = host.env() {
+        let mut env = host_env.clone();
+        env.extend(cmd.env.take().unwrap_or_default());
+        cmd.env = Some(env);
+    }
+    cmd
+}
+
 fn unwanted(hosts: &IndexSet<String>) -> IndexSet<String> {
     utils::as_set(hosts.iter().filter_map(|host| utils::unwanted_host(host)))
 }
@@ -232,21 +325,49 @@ pub struct Hosts {
     /// The hostnames.
     #[get = "pub"]
     hostnames: Vec<String>,
+    /// Environment variables set for every command run on these hosts, when
+    /// the hostlist is named in the run.
+    #[get = "pub"]
+    env: Option<BTreeMap<String, String>>,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
 /// Host configuration.
 pub struct Host {
-    /// A hostname.
-    #[get = "pub"]
-    #[set = "pub"]
-    hostname: String,
+    /// A hostname, or an ordered list of addresses to try.
+    hostname: Hostname,
     /// A pem key.
     #[get = "pub"]
     pem: Option<String>,
+    /// Private key files to try, in order, after the pem key.  `~` and
+    /// `$VAR` are expanded.
+    #[get = "pub"]
+    #[set = "pub"]
+    identity_files: Option<Vec<IdentityFile>>,
+    /// The environment variable holding the private key passphrase, used when
+    /// the auth provider does not supply one.
+    #[get = "pub"]
+    #[set = "pub"]
+    passphrase_env: Option<String>,
+    /// Addresses to try, in order, when the hostname cannot be reached.
+    #[get = "pub"]
+    #[set = "pub"]
+    fallback_addresses: Option<Vec<String>>,
     /// A port
     #[get = "pub"]
     port: Option<u16>,
+    /// The per-address connect timeout, in seconds.
+    #[get = "pub"]
+    #[set = "pub"]
+    connect_timeout: Option<u64>,
+    /// The retry policy for connection and authentication failures.
+    #[get = "pub"]
+    #[set = "pub"]
+    retry: Option<RetryPolicy>,
+    /// The authentication methods to try, in order.
+    #[get = "pub"]
+    #[set = "pub"]
+    auth: Option<Vec<AuthMethod>>,
     /// A username.
     #[get = "pub"]
     #[set = "pub"]
@@ -255,6 +376,198 @@ pub struct Host {
     #[get = "pub"]
     #[set = "pub"]
     alias: Option<Vec<Alias>>,
+    /// Environment variables set for every command run on this host, over
+    /// those of its hostlists.
+    #[get = "pub"]
+    #[set = "pub"]
+    env: Option<BTreeMap<String, String>>,
+    /// How commands reach the host.  By default, only the hostname
+    /// `localhost` runs commands locally, and every other host is reached
+    /// over ssh.
+    #[get = "pub"]
+    #[set = "pub"]
+    transport: Option<Transport>,
+    /// The shell commands are run with, as the program and its arguments,
+    /// such as `["/bin/bash", "-lc"]`.  An empty list runs the words of the
+    /// command line without a shell.  By default, remote commands run under
+    /// the user's login shell, and local commands under `$SHELL -c`, or
+    /// `/bin/sh -c` if it is not set.
+    #[get = "pub"]
+    #[set = "pub"]
+    shell: Option<Vec<String>>,
+}
+
+impl Host {
+    /// The authentication methods to try, in order.  If none are configured,
+    /// the identity files are tried before falling back to the ssh agent.
+    #[must_use]
+    pub fn auth_methods(&self) -> Vec<AuthMethod> {
+        self.auth.clone().unwrap_or_else(|| {
+            if self.identities().is_empty() {
+                vec![AuthMethod::Agent]
+            } else {
+                vec![AuthMethod::PublicKey, AuthMethod::Agent]
+            }
+        })
+    }
+
+    /// The hostname, the first address if the hostname is a list.
+    #[must_use]
+    pub fn hostname(&self) -> &String {
+        match &self.hostname {
+            Hostname::Name(name) => name,
+            Hostname::Addresses(addresses) => addresses.first().unwrap_or(&NO_HOSTNAME),
+        }
+    }
+
+    /// Set the hostname.
+    pub fn set_hostname(&mut self, hostname: String) -> &mut Self {
+        self.hostname = Hostname::Name(hostname);
+        self
+    }
+
+    /// The addresses to connect to, in order: the hostname, or the hostname
+    /// list, then any fallback addresses.
+    #[must_use]
+    pub fn addresses(&self) -> Vec<String> {
+        let mut addresses = match &self.hostname {
+            Hostname::Name(name) => vec![name.clone()],
+            Hostname::Addresses(addresses) => addresses.clone(),
+        };
+        addresses.extend(self.fallback_addresses.iter().flatten().cloned());
+        addresses
+    }
+
+    /// Are commands run on this host locally, rather than over ssh?
+    #[must_use]
+    pub fn is_local(&self) -> bool {
+        match self.transport {
+            None => self.hostname() == "localhost",
+            Some(Transport::Ssh) => false,
+            Some(Transport::Local) => true,
+            Some(Transport::Auto) => utils::is_own_host(self.hostname()),
+        }
+    }
+
+    /// The expanded paths of the private keys to try, in order.
+    #[must_use]
+    pub fn identities(&self) -> Vec<PathBuf> {
+        self.pem
+            .iter()
+            .map(String::as_str)
+            .chain(self.identity_files.iter().flatten().map(IdentityFile::path))
+            .map(utils::expand_path)
+            .collect()
+    }
+
+    /// The expanded path of the public key configured for the given private
+    /// key, if there is one.
+    #[must_use]
+    pub fn public_key(&self, identity: &Path) -> Option<PathBuf> {
+        self.identity_files
+            .iter()
+            .flatten()
+            .find(|file| utils::expand_path(file.path()) == identity)
+            .and_then(|file| file.public_key().map(utils::expand_path))
+    }
+}
+
+/// The hostname of a host configured with an empty list of addresses.
+static NO_HOSTNAME: String = String::new();
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(untagged)]
+/// hostname configuration.
+pub enum Hostname {
+    /// A single hostname or address
+    Name(String),
+    /// Addresses to try, in order
+    Addresses(Vec<String>),
+}
+
+impl Default for Hostname {
+    fn default() -> Self {
+        Hostname::Name(String::new())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(untagged)]
+/// identity file configuration.
+pub enum IdentityFile {
+    /// A private key, with its public key, if any, alongside it with a `.pub`
+    /// extension
+    Path(String),
+    /// A private key, and the public key that goes with it
+    Pair {
+        /// The private key
+        path: String,
+        /// The public key
+        public_key: String,
+    },
+}
+
+impl IdentityFile {
+    /// The path of the private key.
+    #[must_use]
+    pub fn path(&self) -> &str {
+        match self {
+            IdentityFile::Path(path) | IdentityFile::Pair { path, .. } => path,
+        }
+    }
+
+    /// The path of the public key, if it is configured.
+    #[must_use]
+    pub fn public_key(&self) -> Option<&str> {
+        match self {
+            IdentityFile::Path(_) => None,
+            IdentityFile::Pair { public_key, .. } => Some(public_key),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+/// host transport configuration.
+pub enum Transport {
+    /// Run commands over ssh, even on this host
+    Ssh,
+    /// Run commands on this host, whatever the hostname
+    Local,
+    /// Run commands locally if the hostname is a loopback address, or one of
+    /// this host's own names or addresses, and over ssh otherwise.  The first
+    /// host to use it looks this host's names and addresses up by running
+    /// `hostname`, which can block.
+    Auto,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+/// ssh authentication method configuration.
+pub enum AuthMethod {
+    /// Authenticate with the identities held by the ssh agent.
+    Agent,
+    /// Authenticate with a private key file.
+    PublicKey,
+    /// Authenticate with a password.
+    Password,
+    /// Authenticate by answering keyboard-interactive challenges.
+    KeyboardInteractive,
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                AuthMethod::Agent => "agent",
+                AuthMethod::PublicKey => "public-key",
+                AuthMethod::Password => "password",
+                AuthMethod::KeyboardInteractive => "keyboard-interactive",
+            }
+        )
+    }
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
@@ -263,7 +576,303 @@ pub struct Command {
     /// A Command.
     #[get = "pub"]
     #[set = "pub"]
+    #[serde(default)]
     command: String,
+    /// Retry this command, using the host retry policy, when it exits
+    /// non-zero.
+    #[get = "pub"]
+    #[set = "pub"]
+    retry_on_failure: Option<bool>,
+    /// Upload a file instead of running the command.
+    #[get = "pub"]
+    #[set = "pub"]
+    upload: Option<Upload>,
+    /// Fetch files instead of running the command.
+    #[get = "pub"]
+    #[set = "pub"]
+    fetch: Option<Fetch>,
+    /// Synchronize a local directory to the host instead of running the
+    /// command.
+    #[get = "pub"]
+    #[set = "pub"]
+    sync: Option<DirSync>,
+    /// Upload and run a local script instead of running the command.
+    #[get = "pub"]
+    #[set = "pub"]
+    script: Option<String>,
+    /// The interpreter the script is run with, such as `python3`.  By
+    /// default the script is run directly, so it needs a `#!` line.
+    #[get = "pub"]
+    #[set = "pub"]
+    interpreter: Option<String>,
+    /// The arguments the script is run with.
+    #[get = "pub"]
+    #[set = "pub"]
+    args: Option<Vec<String>>,
+    /// The input streamed to the command, followed by an end of file.
+    #[get = "pub"]
+    #[set = "pub"]
+    stdin: Option<Stdin>,
+    /// Run the command on a pseudo-terminal.  Only remote hosts have one.
+    #[get = "pub"]
+    #[set = "pub"]
+    pty: Option<bool>,
+    /// Run the command as another user.  Configured as `become`.
+    #[get = "pub"]
+    #[set = "pub"]
+    #[serde(rename = "become")]
+    escalation: Option<Become>,
+    /// Environment variables set for the command, over those of its host.
+    #[get = "pub"]
+    #[set = "pub"]
+    env: Option<BTreeMap<String, String>>,
+    /// The directory the command runs in.
+    #[get = "pub"]
+    #[set = "pub"]
+    cwd: Option<String>,
+    /// The shell the command is run with, over that of its host.
+    #[get = "pub"]
+    #[set = "pub"]
+    shell: Option<Vec<String>>,
+}
+
+impl Command {
+    /// The number of `command`, `upload`, `fetch`, `sync` and `script` set.
+    fn actions(&self) -> usize {
+        [
+            !self.command.is_empty(),
+            self.upload.is_some(),
+            self.fetch.is_some(),
+            self.sync.is_some(),
+            self.script.is_some(),
+        ]
+        .iter()
+        .filter(|set| **set)
+        .count()
+    }
+}
+
+impl From<String> for Command {
+    fn from(command: String) -> Self {
+        Self {
+            command,
+            ..Self::default()
+        }
+    }
+}
+
+impl From<&str> for Command {
+    fn from(command: &str) -> Self {
+        Self::from(command.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+/// privilege escalation configuration.
+pub struct Become {
+    /// The user to run as, `root` by default.
+    #[get = "pub"]
+    #[set = "pub"]
+    user: Option<String>,
+    /// How to become the user, `sudo` by default.
+    #[get = "pub"]
+    #[set = "pub"]
+    method: Option<BecomeMethod>,
+    /// The environment variable holding the password, used when the auth
+    /// provider does not supply one.  Without a password, the command fails
+    /// rather than prompt for one.
+    #[get = "pub"]
+    #[set = "pub"]
+    password_env: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+/// privilege escalation method configuration.
+pub enum BecomeMethod {
+    /// Run the command with `sudo`
+    Sudo,
+    /// Run the command with `su`.  Answering its password prompt needs a
+    /// pseudo-terminal, so with a password it runs on remote hosts only.
+    Su,
+    /// Run the command with `doas`.  Answering its password prompt needs a
+    /// pseudo-terminal, so with a password it runs on remote hosts only.
+    Doas,
+}
+
+impl Default for BecomeMethod {
+    fn default() -> Self {
+        BecomeMethod::Sudo
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+/// command input configuration.
+pub enum Stdin {
+    /// Stream the contents of a local file
+    File(String),
+    /// Stream the given text
+    Text(String),
+    /// Stream the given bytes
+    Bytes(Vec<u8>),
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+/// file fetch configuration
+pub struct Fetch {
+    /// The remote files to fetch.  Path components may contain the
+    /// wildcards `*`, `?` and `[...]`.
+    #[get = "pub"]
+    #[set = "pub"]
+    source: String,
+    /// The local directory to fetch into, `out` by default.  Each file is
+    /// written to `<dest>/<host>/<path>`.
+    #[get = "pub"]
+    #[set = "pub"]
+    dest: Option<String>,
+    /// Skip files larger than this many bytes.
+    #[get = "pub"]
+    #[set = "pub"]
+    max_size: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+/// directory sync configuration
+pub struct DirSync {
+    /// The local directory to synchronize from.
+    #[get = "pub"]
+    #[set = "pub"]
+    source: String,
+    /// The remote directory to synchronize to.
+    #[get = "pub"]
+    #[set = "pub"]
+    dest: String,
+    /// Delete remote files that are not in the local directory.
+    #[get = "pub"]
+    #[set = "pub"]
+    delete: Option<bool>,
+    /// Skip paths matching these patterns on both sides.  A pattern without
+    /// a `/` matches a file name anywhere, and one with a `/` matches the
+    /// whole path relative to the directory.
+    #[get = "pub"]
+    #[set = "pub"]
+    exclude: Option<Vec<String>>,
+    /// Also compare files of the same size and modification time by their
+    /// SHA-256 checksum, computed by `sha256sum` on each host.
+    #[get = "pub"]
+    #[set = "pub"]
+    checksum: Option<bool>,
+    /// List the differences without changing anything.
+    #[get = "pub"]
+    #[set = "pub"]
+    check: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+/// file upload configuration
+pub struct Upload {
+    /// The local file to upload.
+    #[get = "pub"]
+    #[set = "pub"]
+    source: String,
+    /// The remote path to upload to.
+    #[get = "pub"]
+    #[set = "pub"]
+    dest: String,
+    /// The permissions of the uploaded file, in octal, such as "0644".
+    #[get = "pub"]
+    #[set = "pub"]
+    mode: Option<String>,
+    /// The owner of the uploaded file, as "user" or "user:group".
+    #[get = "pub"]
+    #[set = "pub"]
+    owner: Option<String>,
+    /// Upload to a temporary file beside the destination, and rename it into
+    /// place once it is complete.
+    #[get = "pub"]
+    #[set = "pub"]
+    atomic: Option<bool>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
+#[serde(default)]
+/// retry policy configuration.
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first.
+    #[get = "pub"]
+    #[set = "pub"]
+    attempts: u32,
+    /// The delay before the first retry, in milliseconds.
+    #[get = "pub"]
+    #[set = "pub"]
+    initial_delay: u64,
+    /// The longest delay between attempts, in milliseconds.
+    #[get = "pub"]
+    #[set = "pub"]
+    max_delay: u64,
+    /// The factor the delay grows by after each retry.
+    #[get = "pub"]
+    #[set = "pub"]
+    multiplier: u32,
+    /// The percentage of each delay that is randomized.
+    #[get = "pub"]
+    #[set = "pub"]
+    jitter: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            initial_delay: 1000,
+            max_delay: 30000,
+            multiplier: 2,
+            jitter: 0,
+        }
+    }
+}
+
+impl Stdin {
+    /// A reader over the input.
+    crate fn reader(&self) -> MusshResult<Box<dyn Read + Send>> {
+        Ok(match self {
+            Stdin::File(path) => Box::new(File::open(utils::expand_path(path))?),
+            Stdin::Text(text) => Box::new(Cursor::new(text.clone().into_bytes())),
+            Stdin::Bytes(bytes) => Box::new(Cursor::new(bytes.clone())),
+        })
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before the given retry (1 being the first retry), without
+    /// jitter.
+    #[must_use]
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = u64::from(self.multiplier).saturating_pow(retry.saturating_sub(1));
+        Duration::from_millis(
+            self.initial_delay
+                .saturating_mul(factor)
+                .min(self.max_delay),
+        )
+    }
+
+    /// The delay before the given retry (1 being the first retry), with up to
+    /// `jitter` percent of it randomly removed.
+    #[must_use]
+    pub fn delay(&self, retry: u32) -> Duration {
+        let backoff = self.backoff(retry);
+        let jitter = u64::from(self.jitter.min(100));
+
+        if jitter == 0 {
+            backoff
+        } else {
+            let millis = u64::try_from(backoff.as_millis()).unwrap_or(u64::MAX);
+            let spread = millis / 100 * jitter + millis % 100 * jitter / 100;
+            let random = RandomState::new().build_hasher().finish();
+            Duration::from_millis(millis - random % (spread + 1))
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize, Setters)]
@@ -281,13 +890,21 @@ pub struct Alias {
 
 #[cfg(test)]
 crate mod test {
-    use super::{Alias, Command, Host, Hosts, HostsCmds, Mussh};
+    use super::{
+        Alias, AuthMethod, BecomeMethod, Command, Host, Hostname, Hosts, HostsCmds, Mussh,
+        RetryPolicy, Stdin, Transport,
+    };
     use crate::error::MusshResult;
+    use crate::event::Event;
+    use crate::report::RunReport;
+    use crate::ssh::Metrics;
     use crate::utils::CmdType;
     use clap::{App, Arg};
-    use indexmap::IndexMap;
+    use indexmap::{IndexMap, IndexSet};
     use lazy_static::lazy_static;
     use std::collections::BTreeMap;
+    use std::path::PathBuf;
+    use std::time::Duration;
 
     const ALIAS_TOML: &str = r#"command = "blah"
 aliasfor = "dedah"
@@ -302,6 +919,32 @@ username = "jozias"
 [[alias]]
 command = "blah"
 aliasfor = "dedah"
+"#;
+    const HOST_AUTH_TOML: &str = r#"hostname = "10.0.0.3"
+pem = "abcdef"
+username = "jozias"
+auth = ["public-key", "keyboard-interactive", "password", "agent"]
+"#;
+    const HOST_IDENTITIES_TOML: &str = r#"hostname = "10.0.0.3"
+pem = "abcdef"
+identity_files = [
+    "~/.ssh/id_ed25519",
+    { path = "${HOME}/keys/id_rsa", public_key = "~/keys/rsa.pub" },
+]
+passphrase_env = "MUSSH_PASSPHRASE"
+username = "jozias"
+"#;
+    const HOST_FALLBACK_TOML: &str = r#"hostname = "10.0.0.3"
+fallback_addresses = ["oob-m1.example.com", "10.1.0.3"]
+connect_timeout = 5
+username = "jozias"
+"#;
+    const HOST_RETRY_TOML: &str = r#"hostname = "10.0.0.3"
+username = "jozias"
+
+[retry]
+attempts = 4
+initial_delay = 500
 "#;
     const HOSTS_TOML: &str = r#"hostnames = ["m1", "m2", "m3"]
 "#;
@@ -393,6 +1036,12 @@ command = "uname -a"
             )
     }
 
+    fn command(cmd: &str) -> Command {
+        let mut command = Command::default();
+        let _ = command.set_command(cmd.to_string());
+        command
+    }
+
     lazy_static! {
         static ref ALIAS: Alias = Alias {
             command: "blah".to_string(),
@@ -402,49 +1051,84 @@ command = "uname -a"
             command: "ls.mac".to_string(),
             aliasfor: "ls".to_string(),
         };
-        static ref COMMAND: Command = Command {
-            command: "blah".to_string(),
-        };
+        static ref COMMAND: Command = command("blah");
         static ref HOST_M1_DEF: Host = {
             let alias = ALIAS.clone();
             Host {
-                hostname: "10.0.0.3".to_string(),
+                hostname: Hostname::Name("10.0.0.3".to_string()),
                 pem: Some("abcdef".to_string()),
+                identity_files: None,
+                passphrase_env: None,
+                fallback_addresses: None,
                 port: Some(22),
+                connect_timeout: None,
+                retry: None,
+                auth: None,
                 username: "jozias".to_string(),
                 alias: Some(vec![alias]),
+                env: None,
+                transport: None,
+                shell: None,
             }
         };
         static ref HOST_M1: Host = {
             let alias = ALIAS_1.clone();
             Host {
-                hostname: "10.0.0.3".to_string(),
+                hostname: Hostname::Name("10.0.0.3".to_string()),
                 pem: None,
+                identity_files: None,
+                passphrase_env: None,
+                fallback_addresses: None,
                 port: None,
+                connect_timeout: None,
+                retry: None,
+                auth: None,
                 username: "jozias".to_string(),
                 alias: Some(vec![alias]),
+                env: None,
+                transport: None,
+                shell: None,
             }
         };
         static ref HOST_M2: Host = {
             Host {
-                hostname: "10.0.0.4".to_string(),
+                hostname: Hostname::Name("10.0.0.4".to_string()),
                 pem: None,
+                identity_files: None,
+                passphrase_env: None,
+                fallback_addresses: None,
                 port: None,
+                connect_timeout: None,
+                retry: None,
+                auth: None,
                 username: "jozias".to_string(),
                 alias: None,
+                env: None,
+                transport: None,
+                shell: None,
             }
         };
         static ref HOST_M3: Host = {
             Host {
-                hostname: "10.0.0.5".to_string(),
+                hostname: Hostname::Name("10.0.0.5".to_string()),
                 pem: None,
+                identity_files: None,
+                passphrase_env: None,
+                fallback_addresses: None,
                 port: None,
+                connect_timeout: None,
+                retry: None,
+                auth: None,
                 username: "jozias".to_string(),
                 alias: None,
+                env: None,
+                transport: None,
+                shell: None,
             }
         };
         static ref HOSTS: Hosts = Hosts {
             hostnames: vec!["m1".to_string(), "m2".to_string(), "m3".to_string()],
+            env: None,
         };
         static ref MUSSH: Mussh = {
             let mut hostlist = BTreeMap::new();
@@ -459,28 +1143,28 @@ command = "uname -a"
                 cmd: cmd,
             }
         };
-        static ref EMPTY_CMD_MAP: IndexMap<CmdType, IndexMap<String, String>> = {
+        static ref EMPTY_CMD_MAP: IndexMap<CmdType, IndexMap<String, Command>> = {
             let mut cmd_map = IndexMap::new();
             let _ = cmd_map.insert(CmdType::Cmd, IndexMap::new());
             let _ = cmd_map.insert(CmdType::SyncCmd, IndexMap::new());
             cmd_map
         };
-        static ref ALL_CMD_MAP: IndexMap<CmdType, IndexMap<String, String>> = {
+        static ref ALL_CMD_MAP: IndexMap<CmdType, IndexMap<String, Command>> = {
             let mut cmd_map = IndexMap::new();
             let mut cmds_map = IndexMap::new();
-            let _ = cmds_map.insert("ls".to_string(), "ls -al".to_string());
-            let _ = cmds_map.insert("uname".to_string(), "uname -a".to_string());
-            let _ = cmds_map.insert("bar".to_string(), "bar".to_string());
+            let _ = cmds_map.insert("ls".to_string(), command("ls -al"));
+            let _ = cmds_map.insert("uname".to_string(), command("uname -a"));
+            let _ = cmds_map.insert("bar".to_string(), command("bar"));
             let _ = cmd_map.insert(CmdType::Cmd, cmds_map);
             let _ = cmd_map.insert(CmdType::SyncCmd, IndexMap::new());
             cmd_map
         };
-        static ref SYNC_CMD_MAP: IndexMap<CmdType, IndexMap<String, String>> = {
+        static ref SYNC_CMD_MAP: IndexMap<CmdType, IndexMap<String, Command>> = {
             let mut cmd_map = IndexMap::new();
             let mut cmds_map = IndexMap::new();
-            let _ = cmds_map.insert("ls".to_string(), "ls -al".to_string());
-            let _ = cmds_map.insert("uname".to_string(), "uname -a".to_string());
-            let _ = cmds_map.insert("bar".to_string(), "bar".to_string());
+            let _ = cmds_map.insert("ls".to_string(), command("ls -al"));
+            let _ = cmds_map.insert("uname".to_string(), command("uname -a"));
+            let _ = cmds_map.insert("bar".to_string(), command("bar"));
             let _ = cmd_map.insert(CmdType::Cmd, IndexMap::new());
             let _ = cmd_map.insert(CmdType::SyncCmd, cmds_map);
             cmd_map
@@ -509,6 +1193,47 @@ command = "uname -a"
         Ok(())
     }
 
+    #[test]
+    fn de_command_stdin() -> MusshResult<()> {
+        let actual: Command = toml::from_str(
+            r#"command = "psql"
+stdin = { file = "migration.sql" }
+"#,
+        )?;
+        assert_eq!(
+            *actual.stdin(),
+            Some(Stdin::File("migration.sql".to_string()))
+        );
+        let actual: Command = toml::from_str(
+            r#"command = "tee key"
+stdin = { text = "secret" }
+"#,
+        )?;
+        assert_eq!(*actual.stdin(), Some(Stdin::Text("secret".to_string())));
+        Ok(())
+    }
+
+    #[test]
+    fn de_command_become() -> MusshResult<()> {
+        let actual: Command = toml::from_str(
+            r#"command = "systemctl restart app"
+pty = true
+
+[become]
+user = "app"
+method = "doas"
+password_env = "MUSSH_BECOME"
+"#,
+        )?;
+        assert_eq!(*actual.pty(), Some(true));
+        let escalation = actual.escalation().as_ref().expect("a become block");
+        assert_eq!(*escalation.user(), Some("app".to_string()));
+        assert_eq!(*escalation.method(), Some(BecomeMethod::Doas));
+        assert_eq!(*escalation.password_env(), Some("MUSSH_BECOME".to_string()));
+        assert!(toml::to_string(&actual)?.contains("[become]"));
+        Ok(())
+    }
+
     #[test]
     fn ser_command() -> MusshResult<()> {
         let expected = COMMAND_TOML;
@@ -562,6 +1287,104 @@ command = "uname -a"
         Ok(())
     }
 
+    #[test]
+    fn de_host_auth() -> MusshResult<()> {
+        let actual: Host = toml::from_str(HOST_AUTH_TOML)?;
+        assert_eq!(
+            actual.auth_methods(),
+            vec![
+                AuthMethod::PublicKey,
+                AuthMethod::KeyboardInteractive,
+                AuthMethod::Password,
+                AuthMethod::Agent
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn default_auth_methods() {
+        assert_eq!(
+            HOST_M1_DEF.auth_methods(),
+            vec![AuthMethod::PublicKey, AuthMethod::Agent]
+        );
+        assert_eq!(HOST_M2.auth_methods(), vec![AuthMethod::Agent]);
+    }
+
+    #[test]
+    fn host_identities() -> MusshResult<()> {
+        let actual: Host = toml::from_str(HOST_IDENTITIES_TOML)?;
+        let home = std::env::var("HOME").unwrap_or_default();
+        assert_eq!(
+            actual.identities(),
+            vec![
+                PathBuf::from("abcdef"),
+                PathBuf::from(format!("{}/.ssh/id_ed25519", home)),
+                PathBuf::from(format!("{}/keys/id_rsa", home)),
+            ]
+        );
+        let rsa = PathBuf::from(format!("{}/keys/id_rsa", home));
+        assert_eq!(
+            actual.public_key(&rsa),
+            Some(PathBuf::from(format!("{}/keys/rsa.pub", home)))
+        );
+        assert_eq!(actual.public_key(&actual.identities()[1]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn host_addresses() -> MusshResult<()> {
+        let actual: Host = toml::from_str(HOST_FALLBACK_TOML)?;
+        assert_eq!(
+            actual.addresses(),
+            vec!["10.0.0.3", "oob-m1.example.com", "10.1.0.3"]
+        );
+        assert_eq!(*actual.connect_timeout(), Some(5));
+        assert_eq!(HOST_M2.addresses(), vec!["10.0.0.4"]);
+
+        let actual: Host = toml::from_str(
+            r#"hostname = ["10.0.0.3", "10.1.0.3"]
+fallback_addresses = ["oob-m1.example.com"]
+username = "jozias"
+"#,
+        )?;
+        assert_eq!(actual.hostname(), "10.0.0.3");
+        assert_eq!(
+            actual.addresses(),
+            vec!["10.0.0.3", "10.1.0.3", "oob-m1.example.com"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn de_retry_policy() -> MusshResult<()> {
+        let actual: Host = toml::from_str(HOST_RETRY_TOML)?;
+        let retry = actual.retry().ok_or("no retry policy")?;
+        assert_eq!(*retry.attempts(), 4);
+        assert_eq!(*retry.initial_delay(), 500);
+        assert_eq!(*retry.max_delay(), 30000);
+        assert_eq!(*retry.jitter(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let mut retry = RetryPolicy::default();
+        let _ = retry.set_initial_delay(500).set_max_delay(3000);
+        assert_eq!(retry.delay(1), Duration::from_millis(500));
+        assert_eq!(retry.delay(2), Duration::from_millis(1000));
+        assert_eq!(retry.delay(3), Duration::from_millis(2000));
+        assert_eq!(retry.delay(4), Duration::from_millis(3000));
+        assert_eq!(retry.delay(40), Duration::from_millis(3000));
+
+        let _ = retry.set_jitter(50);
+        for _ in 0..20 {
+            let delay = retry.delay(2);
+            assert!(delay >= Duration::from_millis(500));
+            assert!(delay <= Duration::from_millis(1000));
+        }
+    }
+
     #[test]
     fn hosts_from_cli() -> MusshResult<()> {
         let mut expected = IndexMap::new();
@@ -613,4 +1436,227 @@ command = "uname -a"
         assert_eq!(config.to_host_map(&hosts_cmds), expected);
         Ok(())
     }
+
+    #[test]
+    fn rerun_host_map() -> MusshResult<()> {
+        let config: Mussh = toml::from_str(MUSSH_FULL_TOML)?;
+        let mut hosts_cmds = HostsCmds::default();
+        let _ = hosts_cmds
+            .set_hosts(as_set(&["m2"]))
+            .set_sync_hosts(as_set(&["m1"]))
+            .set_cmds(as_set(&["ls", "uname"]))
+            .set_sync_cmds(as_set(&["bar"]));
+        let hosts_map = config.to_host_map(&hosts_cmds);
+
+        let mut report = RunReport::new(hosts_cmds.sync_hosts(), &hosts_map);
+        for (host, cmd_name, cmd_type, ok) in &[
+            ("m1", "ls", CmdType::Cmd, true),
+            ("m1", "uname", CmdType::Cmd, true),
+            ("m1", "bar", CmdType::SyncCmd, false),
+            ("m2", "ls", CmdType::Cmd, true),
+            ("m2", "uname", CmdType::Cmd, false),
+        ] {
+            report.record(&Event::CommandFinished {
+                host: host.to_string(),
+                cmd_name: cmd_name.to_string(),
+                cmd_type: *cmd_type,
+                result: if *ok {
+                    Ok(Metrics::default())
+                } else {
+                    Err("failed".into())
+                },
+            });
+        }
+
+        let (sync_hosts, rerun) = config.rerun_host_map(&hosts_cmds, &report);
+        let hostnames: Vec<&String> = rerun.keys().collect();
+        assert_eq!(hostnames, vec!["m2", "m1"]);
+        assert_eq!(sync_hosts, as_set(&["m1"]));
+        let sync_cmds: Vec<&String> = rerun["m1"].1[&CmdType::SyncCmd].keys().collect();
+        assert_eq!(sync_cmds, vec!["bar"]);
+        assert!(rerun["m1"].1[&CmdType::Cmd].is_empty());
+        let cmd_map = &rerun["m2"].1;
+        let cmds: Vec<&String> = cmd_map[&CmdType::Cmd].keys().collect();
+        assert_eq!(cmds, vec!["uname"]);
+        let sync_cmds: Vec<&String> = cmd_map[&CmdType::SyncCmd].keys().collect();
+        assert_eq!(sync_cmds, vec!["bar"]);
+        Ok(())
+    }
+
+    #[test]
+    fn rerun_keeps_hostlist_env() -> MusshResult<()> {
+        let config: Mussh = toml::from_str(
+            r#"[hostlist.all]
+hostnames = ["m1", "m2"]
+
+[hostlist.all.env]
+STAGE = "all"
+
+[hostlist.m1]
+hostnames = ["m1"]
+
+[hostlist.m2]
+hostnames = ["m2"]
+
+[hosts.m1]
+hostname = "10.0.0.3"
+username = "jozias"
+
+[hosts.m2]
+hostname = "10.0.0.4"
+username = "jozias"
+
+[cmd.build]
+command = "make"
+"#,
+        )?;
+        let mut hosts_cmds = HostsCmds::default();
+        let _ = hosts_cmds
+            .set_hosts(as_set(&["all"]))
+            .set_cmds(as_set(&["build"]));
+        let hosts_map = config.to_host_map(&hosts_cmds);
+
+        let mut report = RunReport::new(hosts_cmds.sync_hosts(), &hosts_map);
+        for (host, ok) in &[("m1", false), ("m2", true)] {
+            report.record(&Event::CommandFinished {
+                host: host.to_string(),
+                cmd_name: "build".to_string(),
+                cmd_type: CmdType::Cmd,
+                result: if *ok {
+                    Ok(Metrics::default())
+                } else {
+                    Err("failed".into())
+                },
+            });
+        }
+
+        let (_, rerun) = config.rerun_host_map(&hosts_cmds, &report);
+        let hostnames: Vec<&String> = rerun.keys().collect();
+        assert_eq!(hostnames, vec!["m1"]);
+        let stage = rerun["m1"].1[&CmdType::Cmd]["build"]
+            .env()
+            .as_ref()
+            .and_then(|env| env.get("STAGE").cloned());
+        assert_eq!(stage, Some("all".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn one_action_per_command() -> MusshResult<()> {
+        let config: Mussh = toml::from_str(MUSSH_FULL_TOML)?;
+        assert!(config.validate().is_ok());
+
+        let config: Mussh = toml::from_str(
+            r"[hostlist]
+[hosts]
+[cmd.nothing]
+retry_on_failure = true
+",
+        )?;
+        assert!(config.validate().is_err());
+
+        let config: Mussh = toml::from_str(
+            r#"[hostlist]
+[hosts]
+[cmd.both]
+command = "ls"
+script = "deploy.sh"
+"#,
+        )?;
+        assert!(config.validate().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn host_transport() -> MusshResult<()> {
+        let mut host: Host = toml::from_str(
+            r#"hostname = "10.0.0.3"
+username = "jozias"
+transport = "local"
+"#,
+        )?;
+        assert_eq!(*host.transport(), Some(Transport::Local));
+        assert!(host.is_local());
+        let _ = host.set_transport(Some(Transport::Auto));
+        assert!(!host.is_local());
+        let _ = host.set_hostname("::1".to_string());
+        assert!(host.is_local());
+        let _ = host.set_transport(None);
+        assert!(!host.is_local());
+        let _ = host.set_hostname("localhost".to_string());
+        assert!(host.is_local());
+        let _ = host.set_transport(Some(Transport::Ssh));
+        assert!(!host.is_local());
+        Ok(())
+    }
+
+    #[test]
+    fn env_defaults() -> MusshResult<()> {
+        let config: Mussh = toml::from_str(
+            r#"[hostlist.all]
+hostnames = ["m1", "m2"]
+
+[hostlist.all.env]
+LANG = "C"
+STAGE = "all"
+
+[hostlist.m1]
+hostnames = ["m1"]
+
+[hostlist.m1.env]
+STAGE = "m1"
+
+[hosts.m1]
+hostname = "10.0.0.3"
+username = "jozias"
+
+[hosts.m1.env]
+REGION = "east"
+
+[cmd.build]
+command = "make"
+cwd = "/srv/app"
+
+[cmd.build.env]
+LANG = "en_US.UTF-8"
+"#,
+        )?;
+        let mut hosts_cmds = HostsCmds::default();
+        let _ = hosts_cmds
+            .set_hosts(as_set(&["all"]))
+            .set_cmds(as_set(&["build"]));
+        let hosts_map = config.to_host_map(&hosts_cmds);
+        let env = |hostname: &str| {
+            hosts_map[hostname].1[&CmdType::Cmd]["build"]
+                .env()
+                .clone()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(
+            env("m1"),
+            vec!["LANG=en_US.UTF-8", "REGION=east", "STAGE=all"]
+        );
+        let build = &hosts_map["m1"].1[&CmdType::Cmd]["build"];
+        assert_eq!(*build.cwd(), Some("/srv/app".to_string()));
+        assert!(config.cmd()["build"]
+            .env()
+            .as_ref()
+            .map_or(false, |env| env.len() == 1));
+
+        let _ = hosts_cmds.set_hosts(as_set(&["all", "m1"]));
+        let hosts_map = config.to_host_map(&hosts_cmds);
+        let stage = hosts_map["m1"].1[&CmdType::Cmd]["build"]
+            .env()
+            .as_ref()
+            .and_then(|env| env.get("STAGE").cloned());
+        assert_eq!(stage, Some("m1".to_string()));
+        Ok(())
+    }
+
+    fn as_set(values: &[&str]) -> IndexSet<String> {
+        values.iter().map(ToString::to_string).collect()
+    }
 }
@@ -0,0 +1,423 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Cross-host output diffs
+use crate::aggregate::{host_range, Aggregator, OutputGroup};
+use crate::report::{Outcome, RunReport};
+use crate::utils::CmdType;
+use getset::Getters;
+use std::fmt::{self, Write};
+
+/// The number of unchanged lines shown around each change.
+const CONTEXT: usize = 3;
+
+/// How to pick the output the other hosts are compared against.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Baseline {
+    /// The output shared by the most hosts
+    Majority,
+    /// The output of the given host
+    Host(String),
+}
+
+// Written out, as the toolchain predates `#[default]` on enum variants.
+impl Default for Baseline {
+    fn default() -> Self {
+        Baseline::Majority
+    }
+}
+
+/// A line in a diff.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DiffLine {
+    /// A line both outputs share
+    Context(String),
+    /// A line only in the baseline
+    Removed(String),
+    /// A line only in the host output
+    Added(String),
+}
+
+/// The difference between the baseline and a group of hosts with identical
+/// output.
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+pub struct HostDiff {
+    /// The hosts, in the order they appear in the report
+    #[get = "pub"]
+    hosts: Vec<String>,
+    /// Every line of both outputs, in order
+    #[get = "pub"]
+    lines: Vec<DiffLine>,
+}
+
+impl HostDiff {
+    /// The diff in unified format, with the given number of unchanged lines
+    /// around each change.
+    #[must_use]
+    pub fn unified(&self, baseline: &str, context: usize) -> String {
+        let mut text = format!("--- {}\n+++ {}\n", baseline, host_range(&self.hosts));
+        for (start, end) in hunks(&self.lines, context) {
+            let (old_start, new_start) = positions(&self.lines[..start]);
+            let (old_len, new_len) = positions(&self.lines[start..end]);
+            let _ = writeln!(
+                text,
+                "@@ -{} +{} @@",
+                range(old_start, old_len),
+                range(new_start, new_len)
+            );
+            for line in &self.lines[start..end] {
+                let _ = match line {
+                    DiffLine::Context(line) => writeln!(text, " {}", line),
+                    DiffLine::Removed(line) => writeln!(text, "-{}", line),
+                    DiffLine::Added(line) => writeln!(text, "+{}", line),
+                };
+            }
+        }
+        text
+    }
+}
+
+/// The output of every host where a command succeeded, compared against a
+/// baseline.
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+pub struct DiffReport {
+    /// The host the baseline output came from
+    #[get = "pub"]
+    baseline_host: String,
+    /// The hosts that share the baseline output
+    #[get = "pub"]
+    baseline_hosts: Vec<String>,
+    /// The baseline output
+    #[get = "pub"]
+    baseline: Vec<String>,
+    /// The hosts that differ from the baseline, the largest group first
+    #[get = "pub"]
+    diffs: Vec<HostDiff>,
+    /// The hosts where the command did not succeed, which are not compared
+    #[get = "pub"]
+    unsuccessful: Vec<OutputGroup>,
+}
+
+impl DiffReport {
+    /// Did the command succeed on every host, with the baseline output?
+    #[must_use]
+    pub fn is_uniform(&self) -> bool {
+        self.diffs.is_empty() && self.unsuccessful.is_empty()
+    }
+}
+
+impl fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let baseline = format!("{} (baseline)", host_range(&self.baseline_hosts));
+        for diff in &self.diffs {
+            write!(f, "{}", diff.unified(&baseline, CONTEXT))?;
+        }
+        for group in &self.unsuccessful {
+            writeln!(f, "*** {} ({})", group.host_range(), group.outcome())?;
+        }
+        Ok(())
+    }
+}
+
+impl Aggregator {
+    /// Compare the output of every host where the given command succeeded
+    /// against the baseline, after normalization.  The hosts where it did
+    /// not succeed are listed, but not compared.  Returns `None` if the
+    /// command succeeded on no host, or not on the baseline host.
+    #[must_use]
+    pub fn diff(
+        &self,
+        report: &RunReport,
+        cmd_type: CmdType,
+        cmd_name: &str,
+        baseline: &Baseline,
+    ) -> Option<DiffReport> {
+        let (mut groups, unsuccessful): (Vec<_>, Vec<_>) = self
+            .aggregate(report, cmd_type, cmd_name)
+            .into_iter()
+            .partition(|group| *group.outcome() == Outcome::Succeeded);
+        let idx = match baseline {
+            Baseline::Majority if !groups.is_empty() => 0,
+            Baseline::Majority => return None,
+            Baseline::Host(host) => groups
+                .iter()
+                .position(|group| group.hosts().contains(host))?,
+        };
+        let base = groups.remove(idx);
+        let baseline_host = match baseline {
+            Baseline::Host(host) => host.clone(),
+            Baseline::Majority => base.hosts().first()?.clone(),
+        };
+
+        let diffs = groups
+            .into_iter()
+            .map(|group| HostDiff {
+                lines: diff_lines(base.lines(), group.lines()),
+                hosts: group.hosts().clone(),
+            })
+            .collect();
+
+        Some(DiffReport {
+            baseline_host,
+            baseline_hosts: base.hosts().clone(),
+            baseline: base.lines().clone(),
+            diffs,
+            unsuccessful,
+        })
+    }
+}
+
+/// Diff two outputs line by line, using their longest common subsequence.
+fn diff_lines(old: &[String], new: &[String]) -> Vec<DiffLine> {
+    // lcs[i][j] is the length of the LCS of old[i..] and new[j..]
+    let mut lcs = vec![vec![0_usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut lines = Vec::with_capacity(old.len().max(new.len()));
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(DiffLine::Context(old[i].clone()));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            lines.push(DiffLine::Removed(old[i].clone()));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(new[j].clone()));
+            j += 1;
+        }
+    }
+    lines.extend(old[i..].iter().cloned().map(DiffLine::Removed));
+    lines.extend(new[j..].iter().cloned().map(DiffLine::Added));
+    lines
+}
+
+/// The `[start, end)` ranges of the diff lines in each hunk, merging changes
+/// whose context would overlap.
+fn hunks(lines: &[DiffLine], context: usize) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    let changes = lines
+        .iter()
+        .enumerate()
+        .filter(|(_, line)| !matches!(line, DiffLine::Context(_)))
+        .map(|(idx, _)| idx);
+
+    for idx in changes {
+        let start = idx.saturating_sub(context);
+        let end = (idx + 1 + context).min(lines.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
+
+/// The number of baseline and host lines in the diff lines.
+fn positions(lines: &[DiffLine]) -> (usize, usize) {
+    lines.iter().fold((0, 0), |(old, new), line| match line {
+        DiffLine::Context(_) => (old + 1, new + 1),
+        DiffLine::Removed(_) => (old + 1, new),
+        DiffLine::Added(_) => (old, new + 1),
+    })
+}
+
+/// A unified diff range, for a hunk that starts after `before` lines.
+fn range(before: usize, len: usize) -> String {
+    if len == 0 {
+        format!("{},0", before)
+    } else if len == 1 {
+        format!("{}", before + 1)
+    } else {
+        format!("{},{}", before + 1, len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{diff_lines, Baseline, DiffLine, HostDiff};
+    use crate::aggregate::Aggregator;
+    use crate::config::{Command, Host};
+    use crate::event::{Event, OutputStream};
+    use crate::report::RunReport;
+    use crate::ssh::Metrics;
+    use crate::utils::CmdType;
+    use indexmap::{IndexMap, IndexSet};
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(ToString::to_string).collect()
+    }
+
+    fn report(outputs: &[(&str, &str)]) -> RunReport {
+        report_with(outputs, &[])
+    }
+
+    /// A report of `resolv` with the given output, which fails on the
+    /// failed hosts and succeeds elsewhere.
+    fn report_with(outputs: &[(&str, &str)], failed: &[&str]) -> RunReport {
+        let mut hosts_map = IndexMap::new();
+        for (hostname, _) in outputs {
+            let mut cmds = IndexMap::new();
+            let _ = cmds.insert("resolv".to_string(), Command::default());
+            let mut cmd_map = IndexMap::new();
+            let _ = cmd_map.insert(CmdType::Cmd, cmds);
+            let _ = hosts_map.insert(hostname.to_string(), (Host::default(), cmd_map));
+        }
+
+        let mut report = RunReport::new(&IndexSet::new(), &hosts_map);
+        for (hostname, output) in outputs {
+            for line in output.lines() {
+                report.record(&Event::OutputLine {
+                    host: hostname.to_string(),
+                    cmd_name: "resolv".to_string(),
+                    cmd_type: CmdType::Cmd,
+                    stream: OutputStream::Stdout,
+                    line: line.to_string(),
+                    timestamp: 0,
+                });
+            }
+            report.record(&Event::CommandFinished {
+                host: hostname.to_string(),
+                cmd_name: "resolv".to_string(),
+                cmd_type: CmdType::Cmd,
+                result: if failed.contains(hostname) {
+                    Err("failed".into())
+                } else {
+                    Ok(Metrics::default())
+                },
+            });
+        }
+        report
+    }
+
+    #[test]
+    fn lcs_diff() {
+        let diff = diff_lines(&lines("a\nb\nc"), &lines("a\nx\nc\nd"));
+        assert_eq!(
+            diff,
+            vec![
+                DiffLine::Context("a".to_string()),
+                DiffLine::Removed("b".to_string()),
+                DiffLine::Added("x".to_string()),
+                DiffLine::Context("c".to_string()),
+                DiffLine::Added("d".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn unified_hunks() {
+        let old = lines("1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12");
+        let new = lines("1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13");
+        let mut changed = old.clone();
+        changed[0] = "one".to_string();
+        let diff = HostDiff {
+            hosts: vec!["m2".to_string()],
+            lines: diff_lines(&old, &changed),
+        };
+        assert_eq!(
+            diff.unified("m1", 1),
+            "--- m1\n+++ m2\n@@ -1,2 +1,2 @@\n-1\n+one\n 2\n"
+        );
+
+        let diff = HostDiff {
+            hosts: vec!["m2".to_string()],
+            lines: diff_lines(&old, &new),
+        };
+        assert_eq!(
+            diff.unified("m1", 2),
+            "--- m1\n+++ m2\n@@ -11,2 +11,3 @@\n 11\n 12\n+13\n"
+        );
+
+        let diff = HostDiff {
+            hosts: vec!["m2".to_string()],
+            lines: diff_lines(&[], &lines("a")),
+        };
+        assert_eq!(diff.unified("m1", 3), "--- m1\n+++ m2\n@@ -0,0 +1 @@\n+a\n");
+    }
+
+    #[test]
+    fn majority_baseline() {
+        let report = report(&[
+            ("web01", "nameserver 10.0.0.1\nsearch example.com"),
+            ("web02", "nameserver 10.0.0.1\nsearch example.com"),
+            ("web03", "nameserver 10.0.0.9\nsearch example.com"),
+        ]);
+        let diff = Aggregator::default()
+            .diff(&report, CmdType::Cmd, "resolv", &Baseline::Majority)
+            .expect("the command ran");
+        assert_eq!(diff.baseline_host(), "web01");
+        assert_eq!(*diff.baseline_hosts(), vec!["web01", "web02"]);
+        assert_eq!(diff.diffs().len(), 1);
+        assert_eq!(*diff.diffs()[0].hosts(), vec!["web03"]);
+        assert_eq!(
+            diff.to_string(),
+            "--- web[01-02] (baseline)\n+++ web03\n@@ -1,2 +1,2 @@\n-nameserver 10.0.0.1\n+nameserver 10.0.0.9\n search example.com\n"
+        );
+    }
+
+    #[test]
+    fn explicit_baseline() {
+        let report = report(&[("m1", "a"), ("m2", "a"), ("m3", "b")]);
+        let aggregator = Aggregator::default();
+        let diff = aggregator
+            .diff(
+                &report,
+                CmdType::Cmd,
+                "resolv",
+                &Baseline::Host("m3".to_string()),
+            )
+            .expect("the command ran");
+        assert_eq!(diff.baseline_host(), "m3");
+        assert_eq!(*diff.diffs()[0].hosts(), vec!["m1", "m2"]);
+        assert!(!diff.is_uniform());
+
+        assert!(aggregator
+            .diff(
+                &report,
+                CmdType::Cmd,
+                "resolv",
+                &Baseline::Host("m4".to_string())
+            )
+            .is_none());
+        assert!(aggregator
+            .diff(&report, CmdType::Cmd, "ls", &Baseline::Majority)
+            .is_none());
+    }
+
+    #[test]
+    fn failed_hosts_are_not_diffed() {
+        let report = report_with(&[("m1", "a"), ("m2", "a"), ("m3", "")], &["m3"]);
+        let aggregator = Aggregator::default();
+        let diff = aggregator
+            .diff(&report, CmdType::Cmd, "resolv", &Baseline::Majority)
+            .expect("the command succeeded");
+        assert!(diff.diffs().is_empty());
+        assert_eq!(*diff.unsuccessful()[0].hosts(), vec!["m3"]);
+        assert!(!diff.is_uniform());
+        assert_eq!(diff.to_string(), "*** m3 (failed)\n");
+
+        assert!(aggregator
+            .diff(
+                &report,
+                CmdType::Cmd,
+                "resolv",
+                &Baseline::Host("m3".to_string())
+            )
+            .is_none());
+    }
+}
@@ -7,8 +7,15 @@
 // modified, or distributed except according to those terms.
 
 //! Error Handling
+use crate::auth::AuthFailure;
+use serde::{Serialize, Serializer};
+use ssh2::ErrorCode;
 use std::error::Error;
 use std::fmt;
+use std::io::ErrorKind;
+
+/// The libssh2 error code for a timed out blocking call.
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
 
 /// A result that includes a `mussh::Error`
 pub type MusshResult<T> = Result<T, MusshErr>;
@@ -20,12 +27,57 @@ pub struct MusshErr {
     inner: MusshErrKind,
 }
 
+impl MusshErr {
+    /// Did a command run to completion, but exit non-zero?
+    crate fn is_non_zero(&self) -> bool {
+        matches!(self.inner, MusshErrKind::NonZero(..))
+    }
+
+    /// The exit code of a command that ran to completion but exited
+    /// non-zero.  A command killed by a signal has no exit code.
+    #[must_use]
+    pub fn exit_code(&self) -> Option<i32> {
+        match &self.inner {
+            MusshErrKind::NonZero(_, code) => *code,
+            MusshErrKind::Retries(_, inner) => inner.exit_code(),
+            _ => None,
+        }
+    }
+
+    /// Did the operation fail because it timed out?
+    #[must_use]
+    pub fn is_timeout(&self) -> bool {
+        match &self.inner {
+            MusshErrKind::Io(e) => e.kind() == ErrorKind::TimedOut,
+            MusshErrKind::Retries(_, inner) => inner.is_timeout(),
+            MusshErrKind::Ssh2(e) => e.code() == ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT),
+            MusshErrKind::SshConnect(failures) => {
+                !failures.is_empty()
+                    && failures
+                        .iter()
+                        .all(|(_, e)| e.kind() == ErrorKind::TimedOut)
+            }
+            _ => false,
+        }
+    }
+}
+
 impl Error for MusshErr {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         Some(&self.inner)
     }
 }
 
+/// Errors serialize as their display string.
+impl Serialize for MusshErr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
 impl fmt::Display for MusshErr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let err: &(dyn Error) = self;
@@ -68,6 +120,7 @@ impl From<&str> for MusshErr {
 }
 
 external_error!(clap::Error, MusshErrKind::Clap);
+external_error!(serde_json::Error, MusshErrKind::SerdeJson);
 external_error!(ssh2::Error, MusshErrKind::Ssh2);
 external_error!(std::io::Error, MusshErrKind::Io);
 external_error!(toml::de::Error, MusshErrKind::TomlDe);
@@ -75,18 +128,27 @@ external_error!(toml::ser::Error, MusshErrKind::TomlSer);
 
 #[derive(Debug)]
 crate enum MusshErrKind {
+    CheckpointMismatch(String),
     Clap(clap::Error),
+    InvalidCommand(String),
+    InvalidEnv(String),
+    InvalidMode(String),
     Io(std::io::Error),
-    NonZero(String),
-    ShellNotFound,
+    NoMatch(String),
+    NoTerminal(String),
+    NonZero(String, Option<i32>),
+    Retries(u32, Box<MusshErr>),
+    SerdeJson(serde_json::Error),
     Ssh2(ssh2::Error),
-    SshAuthentication,
+    SshAuthentication(String, Vec<AuthFailure>),
+    SshConnect(Vec<(String, std::io::Error)>),
     SshExec(String),
-    SshSession,
     Spawn,
     Str(String),
+    SyncConflict(String),
     TomlDe(toml::de::Error),
     TomlSer(toml::ser::Error),
+    UnbalancedQuote(String),
 }
 
 impl Error for MusshErrKind {
@@ -94,9 +156,11 @@ impl Error for MusshErrKind {
         match self {
             MusshErrKind::Clap(inner) => inner.source(),
             MusshErrKind::Io(inner) => inner.source(),
+            MusshErrKind::SerdeJson(inner) => inner.source(),
             MusshErrKind::Ssh2(inner) => inner.source(),
             MusshErrKind::TomlDe(inner) => inner.source(),
             MusshErrKind::TomlSer(inner) => inner.source(),
+            MusshErrKind::Retries(_, inner) => Some(&inner.inner),
             _ => None,
         }
     }
@@ -107,9 +171,62 @@ impl fmt::Display for MusshErrKind {
         match self {
             MusshErrKind::Clap(inner) => write!(f, "{}", inner),
             MusshErrKind::Io(inner) => write!(f, "{}", inner),
+            MusshErrKind::SerdeJson(inner) => write!(f, "{}", inner),
             MusshErrKind::Ssh2(inner) => write!(f, "{}", inner),
             MusshErrKind::TomlDe(inner) => write!(f, "{}", inner),
             MusshErrKind::TomlSer(inner) => write!(f, "{}", inner),
+            MusshErrKind::SshAuthentication(target, failures) => {
+                write!(f, "ssh authentication failed for '{}'", target)?;
+                for failure in failures {
+                    writeln!(f)?;
+                    write!(f, "  {}", failure)?;
+                }
+                Ok(())
+            }
+            MusshErrKind::CheckpointMismatch(path) => write!(
+                f,
+                "the checkpoint '{}' was written for a different plan, refusing to resume",
+                path
+            ),
+            MusshErrKind::InvalidCommand(cmd_name) => write!(
+                f,
+                "command '{}' must set exactly one of command, upload, fetch, sync or script",
+                cmd_name
+            ),
+            MusshErrKind::InvalidEnv(name) => {
+                write!(f, "invalid environment variable name '{}'", name)
+            }
+            MusshErrKind::InvalidMode(mode) => write!(
+                f,
+                "invalid file mode '{}', expected octal such as '0644'",
+                mode
+            ),
+            MusshErrKind::SyncConflict(path) => write!(
+                f,
+                "'{}' is a file on one side and a directory on the other",
+                path
+            ),
+            MusshErrKind::UnbalancedQuote(line) => {
+                write!(f, "unbalanced quote in command line '{}'", line)
+            }
+            MusshErrKind::NoMatch(pattern) => write!(f, "no files matched '{}'", pattern),
+            MusshErrKind::NoTerminal(cmd_name) => write!(
+                f,
+                "command '{}' answers a su or doas password prompt, which needs a terminal \
+                 that local commands do not have; use sudo instead",
+                cmd_name
+            ),
+            MusshErrKind::NonZero(msg, Some(code)) => write!(f, "{}: exit code {}", msg, code),
+            MusshErrKind::NonZero(msg, None) => write!(f, "{}: killed by a signal", msg),
+            MusshErrKind::Retries(attempts, _) => write!(f, "gave up after {} attempts", attempts),
+            MusshErrKind::SshConnect(failures) => {
+                write!(f, "unable to connect")?;
+                for (address, e) in failures {
+                    writeln!(f)?;
+                    write!(f, "  {}: {}", address, e)?;
+                }
+                Ok(())
+            }
             _ => Ok(()),
         }
     }
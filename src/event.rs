@@ -0,0 +1,113 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Progress events
+use crate::error::MusshResult;
+use crate::ssh::Metrics;
+use crate::utils::CmdType;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+
+/// The stream a line of command output was read from.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputStream {
+    /// Standard output
+    Stdout,
+    /// Standard error
+    Stderr,
+}
+
+impl fmt::Display for OutputStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                OutputStream::Stdout => "stdout",
+                OutputStream::Stderr => "stderr",
+            }
+        )
+    }
+}
+
+/// An event emitted while multiplexing.  The `host` in each event is the name
+/// of the host in the hosts map.
+#[derive(Debug)]
+pub enum Event {
+    /// A connection to the host is about to be opened.  A connection is
+    /// opened for every command run on a remote host.
+    HostConnecting {
+        /// The host being connected to
+        host: String,
+    },
+    /// A connection to the host was opened and authenticated.
+    HostConnected {
+        /// The host that was connected to
+        host: String,
+        /// The address that was connected to
+        address: String,
+        /// The number of attempts made
+        attempts: u32,
+    },
+    /// A command is about to be run.
+    CommandStarted {
+        /// The host the command is running on
+        host: String,
+        /// The name of the command
+        cmd_name: String,
+        /// The phase the command is running in
+        cmd_type: CmdType,
+    },
+    /// A command wrote a line of output.
+    OutputLine {
+        /// The host the command is running on
+        host: String,
+        /// The name of the command
+        cmd_name: String,
+        /// The phase the command is running in
+        cmd_type: CmdType,
+        /// The stream the line was written to
+        stream: OutputStream,
+        /// The line, without its line ending
+        line: String,
+        /// When the line was read, in milliseconds since the epoch
+        timestamp: i64,
+    },
+    /// A command has finished, successfully or not.
+    CommandFinished {
+        /// The host the command ran on
+        host: String,
+        /// The name of the command
+        cmd_name: String,
+        /// The phase the command ran in
+        cmd_type: CmdType,
+        /// The result of the command
+        result: MusshResult<Metrics>,
+    },
+    /// The host has passed the sync barrier.  Sync hosts pass it once their
+    /// sync commands have run, and all other hosts once every sync host has.
+    PhaseBarrierReached {
+        /// The host that passed the barrier
+        host: String,
+    },
+    /// Every command has been run on the host.
+    HostFinished {
+        /// The host that finished
+        host: String,
+    },
+}
+
+/// Observes the events emitted while multiplexing.
+///
+/// Events are delivered in order, from the thread that called
+/// `Multiplex::multiplex`.
+pub trait Observer: fmt::Debug + Send + Sync {
+    /// Called for every event.
+    fn on_event(&self, event: &Event);
+}
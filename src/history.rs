@@ -0,0 +1,450 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Persistent run history
+use crate::error::MusshResult;
+use crate::event::{Event, Observer};
+use crate::report::Outcome;
+use crate::utils::CmdType;
+use chrono::Utc;
+use getset::{Getters, Setters};
+use indexmap::IndexMap;
+use serde_derive::{Deserialize, Serialize};
+use slog::{error, Logger};
+use slog_try::try_error;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A single command execution, as recorded in the history.
+#[derive(Clone, Debug, Deserialize, Eq, Getters, PartialEq, Serialize)]
+pub struct HistoryEntry {
+    /// The name of the host in the hosts map
+    #[get = "pub"]
+    host: String,
+    /// The name of the command
+    #[get = "pub"]
+    cmd_name: String,
+    /// The phase the command ran in
+    #[get = "pub"]
+    cmd_type: CmdType,
+    /// The outcome of the command
+    #[get = "pub"]
+    outcome: Outcome,
+    /// How long a successful run took
+    #[get = "pub"]
+    duration: Option<Duration>,
+    /// When the command finished, in milliseconds since the epoch
+    #[get = "pub"]
+    timestamp: i64,
+}
+
+/// Selects the history entries to compute statistics over.  Every unset
+/// field matches everything.
+#[derive(Clone, Debug, Default, Eq, Getters, PartialEq, Setters)]
+pub struct HistoryQuery {
+    /// Only the given host
+    #[get = "pub"]
+    #[set = "pub"]
+    host: Option<String>,
+    /// Only the given command
+    #[get = "pub"]
+    #[set = "pub"]
+    cmd_name: Option<String>,
+    /// Only runs that finished at or after this time, in milliseconds since
+    /// the epoch
+    #[get = "pub"]
+    #[set = "pub"]
+    since: Option<i64>,
+    /// Only runs that finished before this time, in milliseconds since the
+    /// epoch
+    #[get = "pub"]
+    #[set = "pub"]
+    until: Option<i64>,
+}
+
+impl HistoryQuery {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        self.host.as_ref().map_or(true, |host| *host == entry.host)
+            && self
+                .cmd_name
+                .as_ref()
+                .map_or(true, |cmd_name| *cmd_name == entry.cmd_name)
+            && self.since.map_or(true, |since| entry.timestamp >= since)
+            && self.until.map_or(true, |until| entry.timestamp < until)
+    }
+}
+
+/// Duration statistics for a single command on a single host.  The
+/// durations only cover the successful runs.
+#[derive(Clone, Copy, Debug, Default, Getters, PartialEq)]
+pub struct HistoryStats {
+    /// The number of runs
+    #[get = "pub"]
+    runs: usize,
+    /// The number of runs that failed or timed out
+    #[get = "pub"]
+    failures: usize,
+    /// The median duration
+    #[get = "pub"]
+    p50: Option<Duration>,
+    /// The 95th percentile duration
+    #[get = "pub"]
+    p95: Option<Duration>,
+    /// The longest duration
+    #[get = "pub"]
+    max: Option<Duration>,
+    /// When the command last succeeded, in milliseconds since the epoch
+    #[get = "pub"]
+    last_success: Option<i64>,
+}
+
+impl HistoryStats {
+    /// The fraction of runs that failed or timed out, from 0 to 1.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn failure_rate(&self) -> f64 {
+        if self.runs == 0 {
+            0.0
+        } else {
+            self.failures as f64 / self.runs as f64
+        }
+    }
+
+    fn from_entries(entries: &[&HistoryEntry]) -> Self {
+        let mut durations: Vec<Duration> =
+            entries.iter().filter_map(|entry| entry.duration).collect();
+        durations.sort();
+
+        Self {
+            runs: entries.len(),
+            failures: entries
+                .iter()
+                .filter(|entry| entry.outcome != Outcome::Succeeded)
+                .count(),
+            p50: percentile(&durations, 50),
+            p95: percentile(&durations, 95),
+            max: durations.last().copied(),
+            last_success: entries
+                .iter()
+                .filter(|entry| entry.outcome == Outcome::Succeeded)
+                .map(|entry| entry.timestamp)
+                .max(),
+        }
+    }
+}
+
+/// The nearest-rank percentile of the sorted durations.
+fn percentile(sorted: &[Duration], pct: usize) -> Option<Duration> {
+    if sorted.is_empty() {
+        None
+    } else {
+        // pct percent of the runs, rounded up
+        let rank = (sorted.len() * pct + 99) / 100;
+        Some(sorted[rank.max(1) - 1])
+    }
+}
+
+/// An append-only history of command executions, stored as newline
+/// delimited JSON.
+///
+/// Add it to a `Multiplex` as an observer to record every command that runs.
+#[derive(Debug)]
+pub struct History {
+    /// The path to the history file
+    path: PathBuf,
+    /// The history file, opened for appending
+    file: Mutex<File>,
+    /// Logs the entries that could not be recorded
+    stderr: Option<Logger>,
+}
+
+impl History {
+    /// Open the history file at the given path, creating it if it does not
+    /// exist.
+    ///
+    /// # Errors
+    /// * The file could not be opened.
+    pub fn open<P: AsRef<Path>>(path: P) -> MusshResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        // Terminate a line cut short, so the next entry starts on its own.
+        if file.metadata()?.len() > 0 {
+            let mut last = [0; 1];
+            let _ = file.seek(SeekFrom::End(-1))?;
+            file.read_exact(&mut last)?;
+            if last[0] != b'\n' {
+                file.write_all(b"\n")?;
+            }
+        }
+        Ok(Self {
+            path,
+            file: Mutex::new(file),
+            stderr: None,
+        })
+    }
+
+    /// The path to the history file.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Set the logger for the entries that could not be recorded while
+    /// observing a run.
+    pub fn set_stderr(&mut self, stderr: Option<Logger>) -> &mut Self {
+        self.stderr = stderr;
+        self
+    }
+
+    /// Append an entry to the history.
+    ///
+    /// # Errors
+    /// * The entry could not be written.
+    pub fn append(&self, entry: &HistoryEntry) -> MusshResult<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().map_err(|_| "history file lock poisoned")?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    /// Every entry in the history, oldest first.  Lines that cannot be
+    /// parsed, such as one cut short by a crash, are skipped.
+    ///
+    /// # Errors
+    /// * The history file could not be read.
+    pub fn entries(&self) -> MusshResult<Vec<HistoryEntry>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            if let Ok(entry) = serde_json::from_str(&line?) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Statistics for every (host, command) pair matching the query, in the
+    /// order each pair first appears in the history.
+    ///
+    /// # Errors
+    /// * The history file could not be read.
+    pub fn stats(
+        &self,
+        query: &HistoryQuery,
+    ) -> MusshResult<IndexMap<(String, String), HistoryStats>> {
+        let entries = self.entries()?;
+        let mut grouped: IndexMap<(String, String), Vec<&HistoryEntry>> = IndexMap::new();
+
+        for entry in entries.iter().filter(|entry| query.matches(entry)) {
+            grouped
+                .entry((entry.host.clone(), entry.cmd_name.clone()))
+                .or_default()
+                .push(entry);
+        }
+
+        Ok(grouped
+            .into_iter()
+            .map(|(key, entries)| (key, HistoryStats::from_entries(&entries)))
+            .collect())
+    }
+}
+
+impl Observer for History {
+    fn on_event(&self, event: &Event) {
+        if let Event::CommandFinished {
+            host,
+            cmd_name,
+            cmd_type,
+            result,
+        } = event
+        {
+            let (duration, timestamp) = match result {
+                Ok(metrics) => (Some(*metrics.duration()), *metrics.timestamp()),
+                Err(_) => (None, Utc::now().timestamp_millis()),
+            };
+            let entry = HistoryEntry {
+                host: host.clone(),
+                cmd_name: cmd_name.clone(),
+                cmd_type: *cmd_type,
+                outcome: Outcome::of(result),
+                duration,
+                timestamp,
+            };
+            if let Err(e) = self.append(&entry) {
+                try_error!(
+                    self.stderr,
+                    "history";
+                    "path" => self.path.display().to_string(),
+                    "host" => host,
+                    "cmd" => cmd_name,
+                    "error" => e.to_string()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{percentile, History, HistoryEntry, HistoryQuery};
+    use crate::config::{Command, Host};
+    use crate::error::MusshResult;
+    use crate::report::Outcome;
+    use crate::ssh::Multiplex;
+    use crate::utils::CmdType;
+    use indexmap::{IndexMap, IndexSet};
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn history_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("mussh-{}-{}.ndjson", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn entry(cmd_name: &str, millis: Option<u64>, timestamp: i64) -> HistoryEntry {
+        HistoryEntry {
+            host: "m1".to_string(),
+            cmd_name: cmd_name.to_string(),
+            cmd_type: CmdType::Cmd,
+            outcome: if millis.is_some() {
+                Outcome::Succeeded
+            } else {
+                Outcome::Failed
+            },
+            duration: millis.map(Duration::from_millis),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn percentiles() {
+        let durations: Vec<Duration> = (1..=20).map(Duration::from_secs).collect();
+        assert_eq!(percentile(&durations, 50), Some(Duration::from_secs(10)));
+        assert_eq!(percentile(&durations, 95), Some(Duration::from_secs(19)));
+        assert_eq!(
+            percentile(&durations[..1], 95),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(percentile(&[], 50), None);
+    }
+
+    #[test]
+    fn stats() -> MusshResult<()> {
+        let path = history_path("stats");
+        let history = History::open(&path)?;
+        history.append(&entry("apt", Some(300), 1_000))?;
+        history.append(&entry("apt", Some(100), 2_000))?;
+        history.append(&entry("apt", None, 3_000))?;
+        history.append(&entry("apt", Some(200), 4_000))?;
+        history.append(&entry("uname", Some(10), 5_000))?;
+
+        // A torn write is skipped
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(b"{\"host\":\"m1\",\"cmd")?;
+
+        let stats = history.stats(&HistoryQuery::default())?;
+        let keys: Vec<&(String, String)> = stats.keys().collect();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(*keys[0], ("m1".to_string(), "apt".to_string()));
+
+        let apt = &stats[&("m1".to_string(), "apt".to_string())];
+        assert_eq!(*apt.runs(), 4);
+        assert_eq!(*apt.failures(), 1);
+        assert_eq!(*apt.p50(), Some(Duration::from_millis(200)));
+        assert_eq!(*apt.p95(), Some(Duration::from_millis(300)));
+        assert_eq!(*apt.max(), Some(Duration::from_millis(300)));
+        assert_eq!(*apt.last_success(), Some(4_000));
+        assert!((apt.failure_rate() - 0.25).abs() < f64::EPSILON);
+
+        let mut query = HistoryQuery::default();
+        let _ = query
+            .set_cmd_name(Some("apt".to_string()))
+            .set_since(Some(2_000))
+            .set_until(Some(4_000));
+        let stats = history.stats(&query)?;
+        let apt = &stats[&("m1".to_string(), "apt".to_string())];
+        assert_eq!(*apt.runs(), 2);
+        assert_eq!(*apt.max(), Some(Duration::from_millis(100)));
+        assert_eq!(*apt.last_success(), Some(2_000));
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn append_after_torn_write() -> MusshResult<()> {
+        let path = history_path("torn");
+        let history = History::open(&path)?;
+        history.append(&entry("apt", Some(300), 1_000))?;
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(b"{\"host\":\"m1\",\"cmd")?;
+        drop(history);
+
+        let history = History::open(&path)?;
+        history.append(&entry("apt", Some(100), 2_000))?;
+        let stats = history.stats(&HistoryQuery::default())?;
+        let apt = &stats[&("m1".to_string(), "apt".to_string())];
+        assert_eq!(*apt.runs(), 2);
+        assert_eq!(*apt.last_success(), Some(2_000));
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn observe_runs() -> MusshResult<()> {
+        let path = history_path("observe");
+        let history = Arc::new(History::open(&path)?);
+
+        let mut host = Host::default();
+        let _ = host.set_hostname("localhost".to_string());
+        let mut cmds = IndexMap::new();
+        for (name, cmd) in &[("ok", "true"), ("fail", "false")] {
+            let mut command = Command::default();
+            let _ = command.set_command(cmd.to_string());
+            let _ = cmds.insert(name.to_string(), command);
+        }
+        let mut cmd_map = IndexMap::new();
+        let _ = cmd_map.insert(CmdType::Cmd, cmds);
+        let mut hosts_map = IndexMap::new();
+        let _ = hosts_map.insert("m1".to_string(), (host, cmd_map));
+
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.add_observer(history.clone());
+        let _ = multiplex.multiplex(&IndexSet::new(), hosts_map);
+
+        let entries = history.entries()?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(*entries[0].cmd_name(), "ok");
+        assert_eq!(*entries[0].outcome(), Outcome::Succeeded);
+        assert!(entries[0].duration().is_some());
+        assert_eq!(*entries[1].outcome(), Outcome::Failed);
+        assert!(entries[1].duration().is_none());
+
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
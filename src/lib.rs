@@ -92,12 +92,36 @@
 #![allow(clippy::module_name_repetitions)]
 #![doc(html_root_url = "https://docs.rs/libmussh/0.1.0")]
 
+mod aggregate;
+mod auth;
+mod checkpoint;
 mod config;
+mod diff;
 mod error;
+mod event;
+mod history;
+mod preflight;
+mod privilege;
+mod progress;
+mod report;
 mod ssh;
+mod transfer;
 mod utils;
 
-pub use self::config::{HostsCmds as RuntimeConfig, Mussh as Config};
+pub use self::aggregate::{host_range, Aggregator, Normalize, OutputGroup};
+pub use self::auth::{AuthPrompt, AuthProvider};
+pub use self::checkpoint::Checkpoint;
+pub use self::config::{
+    AuthMethod, Become, BecomeMethod, Command, DirSync, Fetch, Host, Hostname,
+    HostsCmds as RuntimeConfig, IdentityFile, Mussh as Config, RetryPolicy, Stdin, Transport,
+    Upload,
+};
+pub use self::diff::{Baseline, DiffLine, DiffReport, HostDiff};
 pub use self::error::{MusshErr as Error, MusshResult as Result};
+pub use self::event::{Event, Observer, OutputStream};
+pub use self::history::{History, HistoryEntry, HistoryQuery, HistoryStats};
+pub use self::preflight::Preflight;
+pub use self::progress::{Progress, ProgressTracker, RunningHost};
+pub use self::report::{CommandReport, HostReport, Outcome, RunReport};
 pub use self::ssh::{Metrics, Multiplex};
-pub use self::utils::MultiplexMapType;
+pub use self::utils::{from_command_lines, CmdType, CommandLineMapType, MultiplexMapType};
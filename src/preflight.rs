@@ -0,0 +1,192 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Connectivity preflight checks.
+use crate::config::AuthMethod;
+use crate::ssh::{Multiplex, Worker};
+use crate::utils::{base64, MultiplexMapType};
+use getset::Getters;
+use indexmap::IndexMap;
+use slog::error;
+use slog_try::try_error;
+use ssh2::HashType;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// The result of checking connectivity to a single host.
+#[derive(Clone, Debug, Default, Eq, Getters, PartialEq)]
+pub struct Preflight {
+    /// The name of the host that was checked
+    #[get = "pub"]
+    hostname: String,
+    /// Could the host be connected to and authenticated against?
+    #[get = "pub"]
+    reachable: bool,
+    /// The address that was connected to
+    #[get = "pub"]
+    address: Option<String>,
+    /// The authentication method that succeeded
+    #[get = "pub"]
+    auth_method: Option<AuthMethod>,
+    /// The SHA256 fingerprint of the server host key
+    #[get = "pub"]
+    host_key: Option<String>,
+    /// The server banner
+    #[get = "pub"]
+    banner: Option<String>,
+    /// The time taken to connect and handshake
+    #[get = "pub"]
+    latency: Duration,
+    /// The number of attempts made
+    #[get = "pub"]
+    attempts: u32,
+    /// Why the host could not be reached
+    #[get = "pub"]
+    error: Option<String>,
+}
+
+impl Multiplex {
+    /// Connect, handshake, and authenticate to every host in parallel,
+    /// without running any commands.  The results are in the same order as
+    /// the hosts map.
+    #[must_use]
+    pub fn preflight(&self, hosts_map: &MultiplexMapType) -> IndexMap<String, Preflight> {
+        let (tx, rx) = mpsc::channel();
+
+        for (hostname, (host, _)) in hosts_map {
+            let tx_cl = tx.clone();
+            let worker = self.worker(hostname, host.clone());
+            let hostname = hostname.clone();
+
+            let _ = thread::spawn(move || {
+                let preflight = check(hostname.clone(), &worker);
+                tx_cl
+                    .send((hostname, preflight))
+                    .expect("unable to send response");
+            });
+        }
+        drop(tx);
+
+        let mut checked: IndexMap<String, Preflight> = rx.iter().collect();
+        hosts_map
+            .keys()
+            .filter_map(|hostname| checked.swap_remove_entry(hostname))
+            .collect()
+    }
+
+    /// Remove the hosts that fail the preflight check from the hosts map.
+    crate fn drop_unreachable(&self, mut hosts_map: MultiplexMapType) -> MultiplexMapType {
+        for (hostname, preflight) in self.preflight(&hosts_map) {
+            if !preflight.reachable {
+                try_error!(
+                    self.stderr(),
+                    "preflight";
+                    "host" => &hostname,
+                    "message" => "Skipping unreachable host",
+                    "error" => preflight.error.unwrap_or_default()
+                );
+                let _ = hosts_map.shift_remove(&hostname);
+            }
+        }
+        hosts_map
+    }
+}
+
+fn check(hostname: String, worker: &Worker) -> Preflight {
+    let mut preflight = Preflight {
+        hostname,
+        attempts: 1,
+        ..Preflight::default()
+    };
+
+    if worker.host.is_local() {
+        preflight.reachable = true;
+        preflight.address = Some(worker.host.hostname().clone());
+        return preflight;
+    }
+
+    match worker.open_session() {
+        Ok(conn) => {
+            preflight.reachable = true;
+            preflight.address = Some(conn.address);
+            preflight.auth_method = Some(conn.method);
+            preflight.host_key = conn
+                .sess
+                .host_key_hash(HashType::Sha256)
+                .map(|hash| format!("SHA256:{}", base64(hash)));
+            preflight.banner = conn.sess.banner().map(ToString::to_string);
+            preflight.latency = conn.latency;
+            preflight.attempts = conn.attempts;
+        }
+        Err((attempts, e)) => {
+            preflight.attempts = attempts;
+            preflight.error = Some(e.to_string());
+        }
+    }
+    preflight
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::{Host, RetryPolicy};
+    use crate::error::MusshResult;
+    use crate::ssh::Multiplex;
+    use crate::utils::MultiplexMapType;
+    use indexmap::IndexMap;
+    use std::collections::BTreeMap;
+
+    const PREFLIGHT_TOML: &str = r#"[local]
+hostname = "localhost"
+username = "jozias"
+
+[closed]
+hostname = "127.0.0.1"
+port = 1
+username = "jozias"
+"#;
+
+    fn hosts_map() -> MusshResult<MultiplexMapType> {
+        let mut hosts: BTreeMap<String, Host> = toml::from_str(PREFLIGHT_TOML)?;
+        let mut hosts_map = IndexMap::new();
+        for name in &["closed", "local"] {
+            let host = hosts.remove(*name).ok_or("missing host")?;
+            let _ = hosts_map.insert(name.to_string(), (host, IndexMap::new()));
+        }
+        Ok(hosts_map)
+    }
+
+    #[test]
+    fn preflight() -> MusshResult<()> {
+        let mut retry = RetryPolicy::default();
+        let _ = retry.set_attempts(2).set_initial_delay(1);
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.set_retry(retry);
+        let preflight = multiplex.preflight(&hosts_map()?);
+        let hostnames: Vec<&String> = preflight.keys().collect();
+        assert_eq!(hostnames, vec!["closed", "local"]);
+
+        let closed = &preflight["closed"];
+        assert!(!closed.reachable());
+        assert_eq!(*closed.attempts(), 2);
+        assert!(closed.error().is_some());
+
+        let local = &preflight["local"];
+        assert!(local.reachable());
+        assert_eq!(*local.address(), Some("localhost".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn drop_unreachable() -> MusshResult<()> {
+        let hosts_map = Multiplex::default().drop_unreachable(hosts_map()?);
+        let hostnames: Vec<&String> = hosts_map.keys().collect();
+        assert_eq!(hostnames, vec!["local"]);
+        Ok(())
+    }
+}
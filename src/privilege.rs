@@ -0,0 +1,243 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Privilege escalation
+use crate::config::{Become, BecomeMethod};
+use crate::utils::shell_quote;
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+
+/// The user commands become when none is configured.
+const DEFAULT_USER: &str = "root";
+
+/// The user the command runs as.
+crate fn user(config: &Become) -> &str {
+    config.user().as_ref().map_or(DEFAULT_USER, String::as_str)
+}
+
+/// A password that is never shown by `Debug`, so it cannot reach a log by
+/// accident.
+#[derive(Clone)]
+crate struct Password(String);
+
+impl Password {
+    crate fn new(password: String) -> Self {
+        Self(password)
+    }
+
+    /// The password followed by the newline that submits it.
+    fn line(&self) -> Vec<u8> {
+        let mut line = self.0.clone().into_bytes();
+        line.push(b'\n');
+        line
+    }
+}
+
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Password(..)")
+    }
+}
+
+/// What to do after feeding output to a `PromptWatcher`.
+#[derive(Debug, Eq, PartialEq)]
+crate enum Step {
+    /// Keep reading
+    Wait,
+    /// Write these bytes, the password line, to the command
+    Answer(Vec<u8>),
+    /// The password was refused, close the input so the command gives up
+    Refused,
+    /// The command is running as the user, and these bytes are its output
+    Ready(Vec<u8>),
+}
+
+/// Watches the output of a wrapped command until it is running as the user,
+/// answering the password prompt once.  The password is only ever written
+/// in answer to a prompt, so with a passwordless policy it is never sent.
+#[derive(Debug)]
+crate struct PromptWatcher {
+    method: BecomeMethod,
+    prompt: String,
+    ready: String,
+    password: Password,
+    answered: bool,
+    buffer: Vec<u8>,
+}
+
+impl PromptWatcher {
+    crate fn feed(&mut self, chunk: &[u8]) -> Step {
+        self.buffer.extend_from_slice(chunk);
+        let text = String::from_utf8_lossy(&self.buffer).to_string();
+
+        if let Some(idx) = text.find(&self.ready) {
+            let rest = &text[idx + self.ready.len()..];
+            let rest = rest
+                .strip_prefix("\r\n")
+                .or_else(|| rest.strip_prefix('\n'))
+                .unwrap_or(rest);
+            self.buffer.clear();
+            return Step::Ready(rest.as_bytes().to_vec());
+        }
+
+        if self.is_prompt(&text) {
+            self.buffer.clear();
+            if self.answered {
+                return Step::Refused;
+            }
+            self.answered = true;
+            return Step::Answer(self.password.line());
+        }
+        Step::Wait
+    }
+
+    /// Does answering the prompt need a pseudo-terminal?  Only `sudo` reads
+    /// the password from its input.
+    crate fn needs_pty(&self) -> bool {
+        self.method != BecomeMethod::Sudo
+    }
+
+    /// The output held back while watching, once the output has ended
+    /// without the command starting.
+    crate fn finish(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    fn is_prompt(&self, text: &str) -> bool {
+        let text = text.trim_end();
+        match self.method {
+            BecomeMethod::Sudo => text.ends_with(&self.prompt),
+            // su and doas prompts cannot be set, and may be translated
+            BecomeMethod::Su | BecomeMethod::Doas => {
+                text.ends_with(':') && text.to_lowercase().contains("assword")
+            }
+        }
+    }
+}
+
+/// A command wrapped to run as another user.
+#[derive(Debug)]
+crate struct Escalation {
+    /// The wrapped command line
+    crate line: String,
+    /// Watches for the password prompt, if there is a password to answer
+    /// it with
+    crate watcher: Option<PromptWatcher>,
+}
+
+/// Wrap the command to run as the configured user.  Without a password, the
+/// command fails rather than waiting for one.
+crate fn escalate(cmd: &str, config: &Become, password: Option<Password>) -> Escalation {
+    let user = shell_quote(user(config));
+    let method = config.method().unwrap_or_default();
+    let nonce = format!("mussh-{:016x}", RandomState::new().build_hasher().finish());
+
+    if let Some(password) = password {
+        let prompt = format!("[{}] password:", nonce);
+        let ready = format!("{}-ready", nonce);
+        // Announce on stderr once running as the user, so the watcher
+        // knows no prompt is coming.
+        let inner = shell_quote(&format!("echo {} >&2; {}", ready, cmd));
+        let line = match method {
+            BecomeMethod::Sudo => format!(
+                "sudo -S -k -p {} -u {} -- sh -c {}",
+                shell_quote(&prompt),
+                user,
+                inner
+            ),
+            BecomeMethod::Su => format!("su {} -c {}", user, inner),
+            BecomeMethod::Doas => format!("doas -u {} sh -c {}", user, inner),
+        };
+        Escalation {
+            line,
+            watcher: Some(PromptWatcher {
+                method,
+                prompt,
+                ready,
+                password,
+                answered: false,
+                buffer: Vec::new(),
+            }),
+        }
+    } else {
+        let inner = shell_quote(cmd);
+        let line = match method {
+            BecomeMethod::Sudo => format!("sudo -n -u {} -- sh -c {}", user, inner),
+            BecomeMethod::Su => format!("su {} -c {}", user, inner),
+            BecomeMethod::Doas => format!("doas -n -u {} sh -c {}", user, inner),
+        };
+        Escalation {
+            line,
+            watcher: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{escalate, Password, Step};
+    use crate::config::{Become, BecomeMethod};
+
+    #[test]
+    fn wrap_without_password() {
+        let mut config = Become::default();
+        let escalation = escalate("id -u", &config, None);
+        assert_eq!(escalation.line, "sudo -n -u 'root' -- sh -c 'id -u'");
+        assert!(escalation.watcher.is_none());
+
+        let _ = config
+            .set_user(Some("postgres".to_string()))
+            .set_method(Some(BecomeMethod::Doas));
+        let escalation = escalate("psql", &config, None);
+        assert_eq!(escalation.line, "doas -n -u 'postgres' sh -c 'psql'");
+    }
+
+    #[test]
+    fn answer_prompt_once() {
+        let password = Password::new("s3cret".to_string());
+        assert_eq!(format!("{:?}", password), "Password(..)");
+        let escalation = escalate("id -u", &Become::default(), Some(password));
+        let mut watcher = escalation.watcher.expect("a watcher");
+        assert!(escalation.line.starts_with("sudo -S -k -p '[mussh-"));
+        assert!(!escalation.line.contains("s3cret"));
+        assert!(!format!("{:?}", watcher).contains("s3cret"));
+
+        let prompt = watcher.prompt.clone();
+        let ready = watcher.ready.clone();
+        let (start, end) = prompt.split_at(4);
+        assert_eq!(watcher.feed(start.as_bytes()), Step::Wait);
+        assert_eq!(
+            watcher.feed(format!("{} ", end).as_bytes()),
+            Step::Answer(b"s3cret\n".to_vec())
+        );
+        assert_eq!(watcher.feed(b"Sorry, try again.\n"), Step::Wait);
+        assert_eq!(watcher.feed(prompt.as_bytes()), Step::Refused);
+        assert_eq!(
+            watcher.feed(format!("{}\r\n0\n", ready).as_bytes()),
+            Step::Ready(b"0\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn su_prompt() {
+        let mut config = Become::default();
+        let _ = config.set_method(Some(BecomeMethod::Su));
+        let password = Password::new("s3cret".to_string());
+        let escalation = escalate("whoami", &config, Some(password));
+        assert!(escalation.line.starts_with("su 'root' -c 'echo mussh-"));
+        let mut watcher = escalation.watcher.expect("a watcher");
+        assert_eq!(watcher.feed(b"Last login: today\n"), Step::Wait);
+        assert_eq!(
+            watcher.feed(b"Password: "),
+            Step::Answer(b"s3cret\n".to_vec())
+        );
+        assert_eq!(watcher.feed(b"su: Authentication failure\n"), Step::Wait);
+        assert_eq!(watcher.finish(), b"su: Authentication failure\n".to_vec());
+    }
+}
@@ -0,0 +1,418 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Progress and ETA estimation
+use crate::event::{Event, Observer};
+use crate::history::HistoryStats;
+use crate::utils::{CmdType, MultiplexMapType};
+use getset::Getters;
+use indexmap::{IndexMap, IndexSet};
+use std::cmp::Reverse;
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A snapshot of the progress of a run.
+#[derive(Clone, Debug, Default, Eq, Getters, PartialEq)]
+pub struct Progress {
+    /// The number of commands that have finished
+    #[get = "pub"]
+    completed: usize,
+    /// The number of commands in the run
+    #[get = "pub"]
+    total: usize,
+    /// The hosts still running, the slowest first
+    #[get = "pub"]
+    running: Vec<RunningHost>,
+    /// The estimated time until every host has finished, if there is enough
+    /// history to estimate it
+    #[get = "pub"]
+    eta: Option<Duration>,
+}
+
+/// A host that is still running.
+#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+pub struct RunningHost {
+    /// The name of the host in the hosts map
+    #[get = "pub"]
+    host: String,
+    /// The command currently running, if any
+    #[get = "pub"]
+    cmd_name: Option<String>,
+    /// How long the host has been running
+    #[get = "pub"]
+    elapsed: Duration,
+    /// The estimated time until the host has finished, including any wait
+    /// for the sync hosts
+    #[get = "pub"]
+    remaining: Option<Duration>,
+}
+
+#[derive(Debug)]
+struct PlannedCmd {
+    cmd_name: String,
+    cmd_type: CmdType,
+    estimate: Option<Duration>,
+    done: bool,
+}
+
+#[derive(Debug)]
+struct HostState {
+    sync_host: bool,
+    commands: Vec<PlannedCmd>,
+    started: Option<Instant>,
+    /// The index of the running command, and when it started
+    current: Option<(usize, Instant)>,
+    barrier_passed: bool,
+    finished: bool,
+}
+
+impl HostState {
+    /// The estimated time left to run the host's unfinished commands in the
+    /// given phase.
+    fn remaining(&self, cmd_type: CmdType, now: Instant) -> Option<Duration> {
+        let mut remaining = Duration::default();
+        for (idx, cmd) in self.commands.iter().enumerate() {
+            if cmd.done || cmd.cmd_type != cmd_type {
+                continue;
+            }
+
+            let mut estimate = cmd.estimate?;
+            if let Some((current, started)) = self.current {
+                if current == idx {
+                    estimate = estimate
+                        .checked_sub(now.duration_since(started))
+                        .unwrap_or_default();
+                }
+            }
+            remaining += estimate;
+        }
+        Some(remaining)
+    }
+}
+
+/// Tracks the progress of a run, estimating the time left from the median
+/// durations in the run history.
+///
+/// Add it to a `Multiplex` as an observer, and call `progress` from another
+/// thread while the run is going.  Commands with no history are estimated at
+/// the average of the commands that have some.
+#[derive(Debug)]
+pub struct ProgressTracker {
+    hosts: Mutex<IndexMap<String, HostState>>,
+}
+
+impl ProgressTracker {
+    /// Create a tracker for the given run, using the statistics from
+    /// `History::stats` to estimate command durations.
+    #[must_use]
+    pub fn new(
+        sync_hosts: &IndexSet<String>,
+        hosts_map: &MultiplexMapType,
+        stats: &IndexMap<(String, String), HistoryStats>,
+    ) -> Self {
+        let medians: Vec<Duration> = stats.values().filter_map(|stats| *stats.p50()).collect();
+        let fallback = if medians.is_empty() {
+            None
+        } else {
+            let count = u32::try_from(medians.len()).unwrap_or(u32::MAX);
+            Some(medians.iter().sum::<Duration>() / count)
+        };
+
+        let hosts = hosts_map
+            .iter()
+            .map(|(hostname, (_, cmd_map))| {
+                let commands = [CmdType::Cmd, CmdType::SyncCmd]
+                    .iter()
+                    .filter_map(|cmd_type| cmd_map.get(cmd_type).map(|cmds| (*cmd_type, cmds)))
+                    .flat_map(|(cmd_type, cmds)| {
+                        cmds.keys().map(move |cmd_name| (cmd_type, cmd_name))
+                    })
+                    .map(|(cmd_type, cmd_name)| PlannedCmd {
+                        cmd_name: cmd_name.clone(),
+                        cmd_type,
+                        estimate: stats
+                            .get(&(hostname.clone(), cmd_name.clone()))
+                            .and_then(|stats| *stats.p50())
+                            .or(fallback),
+                        done: false,
+                    })
+                    .collect();
+                let host_state = HostState {
+                    sync_host: sync_hosts.contains(hostname),
+                    commands,
+                    started: None,
+                    current: None,
+                    barrier_passed: false,
+                    finished: false,
+                };
+                (hostname.clone(), host_state)
+            })
+            .collect();
+
+        Self {
+            hosts: Mutex::new(hosts),
+        }
+    }
+
+    /// A snapshot of the progress of the run.
+    #[must_use]
+    pub fn progress(&self) -> Progress {
+        self.progress_at(Instant::now())
+    }
+
+    crate fn progress_at(&self, now: Instant) -> Progress {
+        let hosts = match self.hosts.lock() {
+            Ok(hosts) => hosts,
+            Err(_) => return Progress::default(),
+        };
+
+        let commands = hosts.values().flat_map(|state| &state.commands);
+        let total = commands.clone().count();
+        let completed = commands.filter(|cmd| cmd.done).count();
+
+        // Hosts that are not sync hosts cannot start their sync commands
+        // until every sync host has passed the barrier.
+        let barrier = hosts
+            .values()
+            .filter(|state| state.sync_host && !state.barrier_passed)
+            .map(|state| {
+                Some(state.remaining(CmdType::Cmd, now)? + state.remaining(CmdType::SyncCmd, now)?)
+            })
+            .try_fold(Duration::default(), |max, remaining| {
+                remaining.map(|remaining| max.max(remaining))
+            });
+
+        let mut running = Vec::new();
+        let mut eta = Some(Duration::default());
+        for (hostname, state) in hosts.iter().filter(|(_, state)| !state.finished) {
+            let cmds = state.remaining(CmdType::Cmd, now);
+            let sync_cmds = state.remaining(CmdType::SyncCmd, now);
+            let remaining = match (cmds, sync_cmds, barrier) {
+                (Some(cmds), Some(sync_cmds), _) if state.sync_host || state.barrier_passed => {
+                    Some(cmds + sync_cmds)
+                }
+                (Some(cmds), Some(sync_cmds), Some(barrier)) => Some(cmds.max(barrier) + sync_cmds),
+                _ => None,
+            };
+            eta = eta.and_then(|eta| remaining.map(|remaining| eta.max(remaining)));
+
+            if let Some(started) = state.started {
+                running.push(RunningHost {
+                    host: hostname.clone(),
+                    cmd_name: state
+                        .current
+                        .map(|(idx, _)| state.commands[idx].cmd_name.clone()),
+                    elapsed: now.duration_since(started),
+                    remaining,
+                });
+            }
+        }
+        running.sort_by_key(|host| (Reverse(host.remaining), Reverse(host.elapsed)));
+
+        Progress {
+            completed,
+            total,
+            running,
+            eta,
+        }
+    }
+
+    crate fn record_at(&self, event: &Event, now: Instant) {
+        let mut hosts = match self.hosts.lock() {
+            Ok(hosts) => hosts,
+            Err(_) => return,
+        };
+
+        match event {
+            Event::HostConnecting { host } => {
+                if let Some(state) = hosts.get_mut(host) {
+                    let _ = state.started.get_or_insert(now);
+                }
+            }
+            Event::CommandStarted {
+                host,
+                cmd_name,
+                cmd_type,
+            } => {
+                if let Some(state) = hosts.get_mut(host) {
+                    let _ = state.started.get_or_insert(now);
+                    state.current = state
+                        .commands
+                        .iter()
+                        .position(|cmd| {
+                            !cmd.done && cmd.cmd_name == *cmd_name && cmd.cmd_type == *cmd_type
+                        })
+                        .map(|idx| (idx, now));
+                }
+            }
+            Event::CommandFinished {
+                host,
+                cmd_name,
+                cmd_type,
+                ..
+            } => {
+                if let Some(state) = hosts.get_mut(host) {
+                    if let Some(cmd) = state.commands.iter_mut().find(|cmd| {
+                        !cmd.done && cmd.cmd_name == *cmd_name && cmd.cmd_type == *cmd_type
+                    }) {
+                        cmd.done = true;
+                    }
+                    state.current = None;
+                }
+            }
+            Event::PhaseBarrierReached { host } => {
+                if let Some(state) = hosts.get_mut(host) {
+                    state.barrier_passed = true;
+                }
+            }
+            Event::HostFinished { host } => {
+                if let Some(state) = hosts.get_mut(host) {
+                    state.finished = true;
+                    state.current = None;
+                    for cmd in &mut state.commands {
+                        cmd.done = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Observer for ProgressTracker {
+    fn on_event(&self, event: &Event) {
+        self.record_at(event, Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ProgressTracker;
+    use crate::config::{Command, Host};
+    use crate::error::MusshResult;
+    use crate::event::Event;
+    use crate::history::{History, HistoryEntry, HistoryQuery};
+    use crate::utils::{CmdType, MultiplexMapType};
+    use indexmap::{IndexMap, IndexSet};
+    use std::env;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    fn hosts_map() -> MultiplexMapType {
+        let mut hosts_map = IndexMap::new();
+        for hostname in &["m1", "m2"] {
+            let mut cmds = IndexMap::new();
+            let _ = cmds.insert("build".to_string(), Command::default());
+            let mut sync_cmds = IndexMap::new();
+            let _ = sync_cmds.insert("deploy".to_string(), Command::default());
+            let mut cmd_map = IndexMap::new();
+            let _ = cmd_map.insert(CmdType::Cmd, cmds);
+            let _ = cmd_map.insert(CmdType::SyncCmd, sync_cmds);
+            let _ = hosts_map.insert(hostname.to_string(), (Host::default(), cmd_map));
+        }
+        hosts_map
+    }
+
+    fn started(host: &str, cmd_name: &str, cmd_type: CmdType) -> Event {
+        Event::CommandStarted {
+            host: host.to_string(),
+            cmd_name: cmd_name.to_string(),
+            cmd_type,
+        }
+    }
+
+    fn finished(host: &str, cmd_name: &str, cmd_type: CmdType) -> Event {
+        Event::CommandFinished {
+            host: host.to_string(),
+            cmd_name: cmd_name.to_string(),
+            cmd_type,
+            result: Err("failed".into()),
+        }
+    }
+
+    fn tracker() -> MusshResult<ProgressTracker> {
+        let path = env::temp_dir().join(format!("mussh-progress-{}.ndjson", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let history = History::open(&path)?;
+        let entries = r#"{"host":"m1","cmd_name":"build","cmd_type":"cmd","outcome":"succeeded","duration":{"secs":10,"nanos":0},"timestamp":1}
+{"host":"m1","cmd_name":"deploy","cmd_type":"sync_cmd","outcome":"succeeded","duration":{"secs":20,"nanos":0},"timestamp":2}
+{"host":"m2","cmd_name":"build","cmd_type":"cmd","outcome":"succeeded","duration":{"secs":3,"nanos":0},"timestamp":3}
+"#;
+        for line in entries.lines() {
+            let entry: HistoryEntry = serde_json::from_str(line)?;
+            history.append(&entry)?;
+        }
+        let stats = history.stats(&HistoryQuery::default())?;
+        fs::remove_file(&path)?;
+
+        let sync_hosts: IndexSet<String> = vec!["m1".to_string()].into_iter().collect();
+        Ok(ProgressTracker::new(&sync_hosts, &hosts_map(), &stats))
+    }
+
+    #[test]
+    fn eta_waits_for_the_barrier() -> MusshResult<()> {
+        let tracker = tracker()?;
+        let start = Instant::now();
+
+        let progress = tracker.progress_at(start);
+        assert_eq!(*progress.total(), 4);
+        assert_eq!(*progress.completed(), 0);
+        assert!(progress.running().is_empty());
+        // m1 runs build then deploy (30s); m2 waits for m1, then runs deploy,
+        // which has no history, so is estimated at the average of 10s, 20s,
+        // and 3s.
+        assert_eq!(*progress.eta(), Some(Duration::from_secs(41)));
+
+        tracker.record_at(&started("m1", "build", CmdType::Cmd), start);
+        tracker.record_at(&started("m2", "build", CmdType::Cmd), start);
+        let later = start + Duration::from_secs(4);
+        tracker.record_at(&finished("m2", "build", CmdType::Cmd), later);
+
+        let progress = tracker.progress_at(later);
+        assert_eq!(*progress.completed(), 1);
+        assert_eq!(*progress.eta(), Some(Duration::from_secs(37)));
+        let running = progress.running();
+        assert_eq!(running.len(), 2);
+        assert_eq!(running[0].host(), "m2");
+        assert_eq!(*running[0].cmd_name(), None);
+        assert_eq!(*running[0].remaining(), Some(Duration::from_secs(37)));
+        assert_eq!(running[1].host(), "m1");
+        assert_eq!(*running[1].cmd_name(), Some("build".to_string()));
+        assert_eq!(*running[1].elapsed(), Duration::from_secs(4));
+        assert_eq!(*running[1].remaining(), Some(Duration::from_secs(26)));
+        Ok(())
+    }
+
+    #[test]
+    fn finished_hosts_are_not_running() -> MusshResult<()> {
+        let tracker = tracker()?;
+        let now = Instant::now();
+        for host in &["m1", "m2"] {
+            tracker.record_at(&started(host, "build", CmdType::Cmd), now);
+            tracker.record_at(
+                &Event::HostFinished {
+                    host: host.to_string(),
+                },
+                now,
+            );
+        }
+
+        let progress = tracker.progress_at(now);
+        assert_eq!(*progress.completed(), 4);
+        assert!(progress.running().is_empty());
+        assert_eq!(*progress.eta(), Some(Duration::default()));
+        Ok(())
+    }
+
+    #[test]
+    fn no_history_no_eta() {
+        let tracker = ProgressTracker::new(&IndexSet::new(), &hosts_map(), &IndexMap::new());
+        assert_eq!(*tracker.progress().eta(), None);
+    }
+}
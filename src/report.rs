@@ -0,0 +1,637 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Run reports
+use crate::config::HostsCmds;
+use crate::error::MusshResult;
+use crate::event::{Event, Observer, OutputStream};
+use crate::ssh::{Metrics, Multiplex};
+use crate::utils::{CmdType, MultiplexMapType};
+use chrono::Utc;
+use getset::Getters;
+use indexmap::{IndexMap, IndexSet};
+use serde_derive::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Read, Write};
+use std::mem;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+mod junit;
+mod prometheus;
+
+/// The outcome of a command.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The command ran and exited zero
+    Succeeded,
+    /// The command could not be run, or exited non-zero
+    Failed,
+    /// The command was never run
+    Skipped,
+    /// The command could not be run because the host timed out
+    TimedOut,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Outcome::Succeeded => "succeeded",
+                Outcome::Failed => "failed",
+                Outcome::Skipped => "skipped",
+                Outcome::TimedOut => "timed out",
+            }
+        )
+    }
+}
+
+impl Outcome {
+    /// The outcome of a command that was run.
+    crate fn of(result: &MusshResult<Metrics>) -> Self {
+        match result {
+            Ok(_) => Outcome::Succeeded,
+            Err(e) if e.is_timeout() => Outcome::TimedOut,
+            Err(_) => Outcome::Failed,
+        }
+    }
+}
+
+/// The report for a single command run on a host.
+#[derive(Clone, Debug, Deserialize, Eq, Getters, PartialEq, Serialize)]
+pub struct CommandReport {
+    /// The name of the command
+    #[get = "pub"]
+    cmd_name: String,
+    /// The phase the command ran in
+    #[get = "pub"]
+    cmd_type: CmdType,
+    /// The outcome of the command
+    #[get = "pub"]
+    outcome: Outcome,
+    /// The metrics of a successful run
+    #[get = "pub"]
+    metrics: Option<Metrics>,
+    /// The error of a failed run
+    #[get = "pub"]
+    error: Option<String>,
+    /// The exit code of a run that exited non-zero
+    #[get = "pub"]
+    exit_code: Option<i32>,
+    /// When the command started, in milliseconds since the epoch
+    #[get = "pub"]
+    #[serde(default)]
+    started: Option<i64>,
+    /// When the command finished, in milliseconds since the epoch
+    #[get = "pub"]
+    finished: Option<i64>,
+    /// The lines the command wrote to stdout
+    #[get = "pub"]
+    stdout: Vec<String>,
+    /// The lines the command wrote to stderr
+    #[get = "pub"]
+    stderr: Vec<String>,
+}
+
+impl CommandReport {
+    fn skipped(cmd_name: &str, cmd_type: CmdType) -> Self {
+        Self {
+            cmd_name: cmd_name.to_string(),
+            cmd_type,
+            outcome: Outcome::Skipped,
+            metrics: None,
+            error: None,
+            exit_code: None,
+            started: None,
+            finished: None,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }
+    }
+
+    /// How long the command took to run: the duration of a successful run,
+    /// or the time between starting and finishing otherwise.
+    #[must_use]
+    pub fn duration(&self) -> Option<Duration> {
+        self.metrics
+            .as_ref()
+            .map(|metrics| *metrics.duration())
+            .or_else(|| match (self.started, self.finished) {
+                (Some(started), Some(finished)) => u64::try_from(finished - started)
+                    .ok()
+                    .map(Duration::from_millis),
+                _ => None,
+            })
+    }
+}
+
+/// The report for every command run on a single host.
+#[derive(Clone, Debug, Deserialize, Eq, Getters, PartialEq, Serialize)]
+pub struct HostReport {
+    /// The hostname configured for the host
+    #[get = "pub"]
+    #[serde(default)]
+    hostname: String,
+    /// Was this a sync host?
+    #[get = "pub"]
+    sync_host: bool,
+    /// The commands, in the order they were planned
+    #[get = "pub"]
+    commands: Vec<CommandReport>,
+}
+
+impl HostReport {
+    /// Did every command on this host succeed?
+    #[must_use]
+    pub fn is_success(&self) -> bool {
+        self.commands
+            .iter()
+            .all(|cmd| cmd.outcome == Outcome::Succeeded)
+    }
+
+    /// The number of commands with the given outcome.
+    #[must_use]
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.commands
+            .iter()
+            .filter(|cmd| cmd.outcome == outcome)
+            .count()
+    }
+
+    fn command_mut(&mut self, cmd_name: &str, cmd_type: CmdType) -> Option<&mut CommandReport> {
+        self.commands
+            .iter_mut()
+            .find(|cmd| cmd.cmd_name == cmd_name && cmd.cmd_type == cmd_type)
+    }
+}
+
+/// The report for a multiplex run, keyed by host in the order of the hosts
+/// map.
+///
+/// Every planned command starts out skipped, and is updated as the events
+/// from the run are recorded.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Getters, PartialEq, Serialize)]
+pub struct RunReport {
+    /// The host reports
+    #[get = "pub"]
+    hosts: IndexMap<String, HostReport>,
+}
+
+impl RunReport {
+    /// Create a report with every command in the plan marked as skipped.
+    #[must_use]
+    pub fn new(sync_hosts: &IndexSet<String>, hosts_map: &MultiplexMapType) -> Self {
+        let hosts = hosts_map
+            .iter()
+            .map(|(hostname, (host, cmd_map))| {
+                let commands = [CmdType::Cmd, CmdType::SyncCmd]
+                    .iter()
+                    .filter_map(|cmd_type| cmd_map.get(cmd_type).map(|cmds| (cmd_type, cmds)))
+                    .flat_map(|(cmd_type, cmds)| {
+                        cmds.keys()
+                            .map(move |cmd_name| CommandReport::skipped(cmd_name, *cmd_type))
+                    })
+                    .collect();
+                let host_report = HostReport {
+                    hostname: host.hostname().clone(),
+                    sync_host: sync_hosts.contains(hostname),
+                    commands,
+                };
+                (hostname.clone(), host_report)
+            })
+            .collect();
+        Self { hosts }
+    }
+
+    /// Update the report with an event from the run.
+    pub fn record(&mut self, event: &Event) {
+        match event {
+            Event::CommandStarted {
+                host,
+                cmd_name,
+                cmd_type,
+            } => {
+                if let Some(cmd) = self.running(host, cmd_name, *cmd_type) {
+                    cmd.started = Some(Utc::now().timestamp_millis());
+                }
+            }
+            Event::OutputLine {
+                host,
+                cmd_name,
+                cmd_type,
+                stream,
+                line,
+                ..
+            } => {
+                if let Some(cmd) = self.running(host, cmd_name, *cmd_type) {
+                    match stream {
+                        OutputStream::Stdout => cmd.stdout.push(line.clone()),
+                        OutputStream::Stderr => cmd.stderr.push(line.clone()),
+                    }
+                }
+            }
+            Event::CommandFinished {
+                host,
+                cmd_name,
+                cmd_type,
+                result,
+            } => {
+                if let Some(cmd) = self
+                    .hosts
+                    .get_mut(host)
+                    .and_then(|host_report| host_report.command_mut(cmd_name, *cmd_type))
+                {
+                    cmd.outcome = Outcome::of(result);
+                    match result {
+                        Ok(metrics) => {
+                            cmd.metrics = Some(metrics.clone());
+                            cmd.finished = Some(*metrics.timestamp());
+                        }
+                        Err(e) => {
+                            cmd.error = Some(e.to_string());
+                            cmd.exit_code = e.exit_code();
+                            cmd.finished = Some(Utc::now().timestamp_millis());
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The first command with the given name in the given phase on the host
+    /// that has not finished yet, which is the one currently running.
+    fn running(
+        &mut self,
+        host: &str,
+        cmd_name: &str,
+        cmd_type: CmdType,
+    ) -> Option<&mut CommandReport> {
+        self.hosts.get_mut(host).and_then(|host_report| {
+            host_report.commands.iter_mut().find(|cmd| {
+                cmd.cmd_name == cmd_name
+                    && cmd.cmd_type == cmd_type
+                    && cmd.outcome == Outcome::Skipped
+                    && cmd.metrics.is_none()
+                    && cmd.error.is_none()
+            })
+        })
+    }
+
+    /// The names of the hosts where any command did not succeed.
+    #[must_use]
+    pub fn failed_hosts(&self) -> Vec<&str> {
+        self.hosts
+            .iter()
+            .filter(|(_, host_report)| !host_report.is_success())
+            .map(|(hostname, _)| &hostname[..])
+            .collect()
+    }
+
+    /// Did every command on every host succeed?
+    #[must_use]
+    pub fn is_success(&self) -> bool {
+        self.hosts.values().all(HostReport::is_success)
+    }
+
+    /// The number of commands, across all hosts, with the given outcome.
+    #[must_use]
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.hosts
+            .values()
+            .map(|host_report| host_report.count(outcome))
+            .sum()
+    }
+
+    /// The number of commands that succeeded.
+    #[must_use]
+    pub fn succeeded(&self) -> usize {
+        self.count(Outcome::Succeeded)
+    }
+
+    /// The number of commands that failed.
+    #[must_use]
+    pub fn failed(&self) -> usize {
+        self.count(Outcome::Failed)
+    }
+
+    /// The number of commands that were skipped.
+    #[must_use]
+    pub fn skipped(&self) -> usize {
+        self.count(Outcome::Skipped)
+    }
+
+    /// The number of commands that timed out.
+    #[must_use]
+    pub fn timed_out(&self) -> usize {
+        self.count(Outcome::TimedOut)
+    }
+
+    /// The total number of commands.
+    #[must_use]
+    pub fn total(&self) -> usize {
+        self.hosts
+            .values()
+            .map(|host_report| host_report.commands.len())
+            .sum()
+    }
+
+    /// The hosts and commands to rerun the commands that did not succeed.
+    ///
+    /// Hosts and commands combine as a cross product, so this may rerun a
+    /// command on a host where it already succeeded.  Use
+    /// `Mussh::rerun_host_map` to rerun exactly the commands that did not
+    /// succeed.
+    #[must_use]
+    pub fn rerun_hosts_cmds(&self) -> HostsCmds {
+        let mut hosts = IndexSet::new();
+        let mut sync_hosts = IndexSet::new();
+        let mut cmds = IndexSet::new();
+        let mut sync_cmds = IndexSet::new();
+
+        for (hostname, host_report) in &self.hosts {
+            let pending: Vec<&CommandReport> = host_report
+                .commands
+                .iter()
+                .filter(|cmd| cmd.outcome != Outcome::Succeeded)
+                .collect();
+
+            if pending.is_empty() {
+                continue;
+            }
+
+            let _ = if host_report.sync_host {
+                sync_hosts.insert(hostname.clone())
+            } else {
+                hosts.insert(hostname.clone())
+            };
+
+            for cmd in pending {
+                let _ = match cmd.cmd_type {
+                    CmdType::Cmd => cmds.insert(cmd.cmd_name.clone()),
+                    CmdType::SyncCmd => sync_cmds.insert(cmd.cmd_name.clone()),
+                };
+            }
+        }
+
+        let mut hosts_cmds = HostsCmds::default();
+        let _ = hosts_cmds
+            .set_hosts(hosts)
+            .set_sync_hosts(sync_hosts)
+            .set_cmds(cmds)
+            .set_sync_cmds(sync_cmds);
+        hosts_cmds
+    }
+
+    /// Read a report written by `write_json`.
+    ///
+    /// # Errors
+    /// * The report could not be read or parsed.
+    pub fn read_json<R: Read>(reader: R) -> MusshResult<Self> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    /// Write the report as a single JSON document.
+    ///
+    /// # Errors
+    /// * The report could not be written.
+    pub fn write_json<W: Write>(&self, writer: W) -> MusshResult<()> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    /// Write the report as newline delimited JSON, one record per command
+    /// that was run.  Skipped commands are left out.
+    ///
+    /// # Errors
+    /// * The report could not be written.
+    pub fn write_ndjson<W: Write>(&self, mut writer: W) -> MusshResult<()> {
+        for (host, host_report) in &self.hosts {
+            for command in &host_report.commands {
+                if command.outcome == Outcome::Skipped {
+                    continue;
+                }
+
+                let record = Record {
+                    host,
+                    sync_host: host_report.sync_host,
+                    command,
+                };
+                serde_json::to_writer(&mut writer, &record)?;
+                writeln!(writer)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A single NDJSON record.
+#[derive(Serialize)]
+struct Record<'a> {
+    host: &'a str,
+    sync_host: bool,
+    #[serde(flatten)]
+    command: &'a CommandReport,
+}
+
+/// Records the events of a run into a report.
+#[derive(Debug)]
+struct Reporter {
+    report: Mutex<RunReport>,
+}
+
+impl Observer for Reporter {
+    fn on_event(&self, event: &Event) {
+        if let Ok(mut report) = self.report.lock() {
+            report.record(event);
+        }
+    }
+}
+
+impl Multiplex {
+    /// Multiplex the requested commands over the requested hosts, and report
+    /// the outcome of every command grouped by host.
+    #[must_use]
+    pub fn run(mut self, sync_hosts: &IndexSet<String>, hosts_map: MultiplexMapType) -> RunReport {
+        let reporter = Arc::new(Reporter {
+            report: Mutex::new(RunReport::new(sync_hosts, &hosts_map)),
+        });
+        let _ = self.add_observer(reporter.clone());
+        let _ = self.multiplex(sync_hosts, hosts_map);
+
+        let mut report = reporter
+            .report
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        mem::take(&mut *report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Outcome, RunReport};
+    use crate::config::{Command, Host};
+    use crate::error::{MusshErr, MusshResult};
+    use crate::event::{Event, OutputStream};
+    use crate::ssh::{Metrics, Multiplex};
+    use crate::utils::{CmdType, MultiplexMapType};
+    use indexmap::{IndexMap, IndexSet};
+    use serde_json::Value;
+    use std::io::{self, ErrorKind};
+
+    fn command(cmd: &str) -> Command {
+        let mut command = Command::default();
+        let _ = command.set_command(cmd.to_string());
+        command
+    }
+
+    fn hosts_map() -> MultiplexMapType {
+        let mut host = Host::default();
+        let _ = host.set_hostname("localhost".to_string());
+        let mut hosts_map = IndexMap::new();
+
+        for (hostname, fail) in &[("m1", "true"), ("m2", "false")] {
+            let mut cmds = IndexMap::new();
+            let _ = cmds.insert("echo".to_string(), command("echo hello"));
+            let _ = cmds.insert("fail".to_string(), command(fail));
+            let mut sync_cmds = IndexMap::new();
+            let _ = sync_cmds.insert("echo".to_string(), command("echo sync"));
+            let mut cmd_map = IndexMap::new();
+            let _ = cmd_map.insert(CmdType::Cmd, cmds);
+            let _ = cmd_map.insert(CmdType::SyncCmd, sync_cmds);
+            let _ = hosts_map.insert(hostname.to_string(), (host.clone(), cmd_map));
+        }
+        hosts_map
+    }
+
+    #[test]
+    fn dry_run_skips_everything() {
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.set_dry_run(true);
+        let report = multiplex.run(&IndexSet::new(), hosts_map());
+        assert_eq!(report.total(), 6);
+        assert_eq!(report.skipped(), 6);
+        assert!(!report.is_success());
+    }
+
+    #[test]
+    fn run_report() {
+        let sync_hosts: IndexSet<String> = vec!["m1".to_string()].into_iter().collect();
+        let report = Multiplex::default().run(&sync_hosts, hosts_map());
+        let hostnames: Vec<&String> = report.hosts().keys().collect();
+        assert_eq!(hostnames, vec!["m1", "m2"]);
+        assert_eq!(report.succeeded(), 5);
+        assert_eq!(report.failed(), 1);
+        assert_eq!(report.failed_hosts(), vec!["m2"]);
+        assert!(!report.is_success());
+
+        let rerun = report.rerun_hosts_cmds();
+        let hosts: Vec<&String> = rerun.hosts().iter().collect();
+        assert_eq!(hosts, vec!["m2"]);
+        assert!(rerun.sync_hosts().is_empty());
+        let cmds: Vec<&String> = rerun.cmds().iter().collect();
+        assert_eq!(cmds, vec!["fail"]);
+        assert!(rerun.sync_cmds().is_empty());
+
+        let m1 = &report.hosts()["m1"];
+        assert!(m1.sync_host());
+        assert!(m1.is_success());
+
+        let m2 = &report.hosts()["m2"];
+        assert!(!m2.sync_host());
+        let commands = m2.commands();
+        assert_eq!(*commands[0].stdout(), vec!["hello"]);
+        assert_eq!(*commands[1].outcome(), Outcome::Failed);
+        assert!(commands[1].error().is_some());
+        assert_eq!(*commands[1].exit_code(), Some(1));
+        assert_eq!(*commands[2].cmd_type(), CmdType::SyncCmd);
+        assert_eq!(*commands[2].stdout(), vec!["sync"]);
+        assert!(commands[2].metrics().is_some());
+    }
+
+    #[test]
+    fn output_by_phase() {
+        let mut report = RunReport::new(&IndexSet::new(), &hosts_map());
+        for (cmd_type, line) in &[(CmdType::SyncCmd, "sync"), (CmdType::Cmd, "hello")] {
+            report.record(&Event::OutputLine {
+                host: "m1".to_string(),
+                cmd_name: "echo".to_string(),
+                cmd_type: *cmd_type,
+                stream: OutputStream::Stdout,
+                line: line.to_string(),
+                timestamp: 0,
+            });
+        }
+        let commands = report.hosts()["m1"].commands();
+        assert_eq!(*commands[0].stdout(), vec!["hello"]);
+        assert_eq!(*commands[2].stdout(), vec!["sync"]);
+    }
+
+    #[test]
+    fn json_round_trip() -> MusshResult<()> {
+        let report = Multiplex::default().run(&IndexSet::new(), hosts_map());
+        let mut buf = Vec::new();
+        report.write_json(&mut buf)?;
+        let parsed = RunReport::read_json(&buf[..])?;
+        assert_eq!(parsed, report);
+        Ok(())
+    }
+
+    #[test]
+    fn ndjson_records() -> MusshResult<()> {
+        let report = Multiplex::default().run(&IndexSet::new(), hosts_map());
+        let mut buf = Vec::new();
+        report.write_ndjson(&mut buf)?;
+        let records = String::from_utf8_lossy(&buf)
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<Value>, _>>()?;
+        assert_eq!(records.len(), 6);
+        assert_eq!(records[1]["host"], "m1");
+        assert_eq!(records[1]["cmd_name"], "fail");
+        assert_eq!(records[1]["cmd_type"], "cmd");
+        assert_eq!(records[1]["outcome"], "succeeded");
+        assert_eq!(records[4]["outcome"], "failed");
+        assert!(records[4]["error"].is_string());
+        assert_eq!(records[5]["cmd_type"], "sync_cmd");
+        assert_eq!(records[5]["stdout"][0], "sync");
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_writes_no_records() -> MusshResult<()> {
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.set_dry_run(true);
+        let mut buf = Vec::new();
+        multiplex
+            .run(&IndexSet::new(), hosts_map())
+            .write_ndjson(&mut buf)?;
+        assert!(buf.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn error_serializes_as_string() -> MusshResult<()> {
+        let result: MusshResult<Metrics> =
+            Err(MusshErr::from(io::Error::new(ErrorKind::Other, "boom")));
+        let value = serde_json::to_value(&result)?;
+        assert_eq!(value["Err"], "libmussh error\nboom");
+        Ok(())
+    }
+
+    #[test]
+    fn empty_report() {
+        let report = RunReport::default();
+        assert!(report.is_success());
+        assert_eq!(report.total(), 0);
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! `JUnit` XML report output
+use super::{CommandReport, HostReport, Outcome, RunReport};
+use crate::error::MusshResult;
+use std::io::Write;
+use std::time::Duration;
+
+impl RunReport {
+    /// Write the report as `JUnit` XML.  Each host is a testsuite, and each
+    /// command run on it a testcase.
+    ///
+    /// Commands that exited non-zero are failures, carrying the exit code and
+    /// the captured stderr.  Commands that could not be run, were killed by
+    /// a signal, or timed out, are errors.  Commands that never ran are skipped.
+    ///
+    /// # Errors
+    /// * The report could not be written.
+    pub fn write_junit<W: Write>(&self, mut writer: W) -> MusshResult<()> {
+        writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        let commands = self
+            .hosts
+            .values()
+            .flat_map(|host_report| &host_report.commands);
+        writeln!(
+            writer,
+            r#"<testsuites name="mussh" {} time="{}">"#,
+            counts(commands),
+            seconds(self.hosts.values().map(duration).sum()),
+        )?;
+
+        for (hostname, host_report) in &self.hosts {
+            write_testsuite(&mut writer, hostname, host_report)?;
+        }
+
+        writeln!(writer, "</testsuites>")?;
+        Ok(())
+    }
+}
+
+fn write_testsuite<W: Write>(
+    writer: &mut W,
+    hostname: &str,
+    host_report: &HostReport,
+) -> MusshResult<()> {
+    writeln!(
+        writer,
+        r#"  <testsuite name="{}" {} time="{}">"#,
+        escape(hostname),
+        counts(&host_report.commands),
+        seconds(duration(host_report)),
+    )?;
+
+    for command in &host_report.commands {
+        write_testcase(writer, hostname, command)?;
+    }
+
+    writeln!(writer, "  </testsuite>")?;
+    Ok(())
+}
+
+fn write_testcase<W: Write>(
+    writer: &mut W,
+    hostname: &str,
+    command: &CommandReport,
+) -> MusshResult<()> {
+    let time = command.duration().unwrap_or_default();
+    writeln!(
+        writer,
+        r#"    <testcase name="{}" classname="{}.{}" time="{}">"#,
+        escape(&command.cmd_name),
+        escape(hostname),
+        command.cmd_type,
+        seconds(time),
+    )?;
+
+    let error = command.error.as_ref().map_or("", String::as_str);
+    match command.outcome {
+        Outcome::Succeeded => {}
+        Outcome::Skipped => writeln!(writer, "      <skipped/>")?,
+        Outcome::Failed => match command.exit_code {
+            Some(code) => writeln!(
+                writer,
+                r#"      <failure message="exit code {}" type="non-zero">{}</failure>"#,
+                code,
+                escape(&command.stderr.join("\n")),
+            )?,
+            None => writeln!(
+                writer,
+                r#"      <error message="{}" type="failed">{}</error>"#,
+                escape(first_line(error)),
+                escape(error),
+            )?,
+        },
+        Outcome::TimedOut => writeln!(
+            writer,
+            r#"      <error message="{}" type="timeout">{}</error>"#,
+            escape(first_line(error)),
+            escape(error),
+        )?,
+    }
+
+    if !command.stdout.is_empty() {
+        writeln!(
+            writer,
+            "      <system-out>{}</system-out>",
+            escape(&command.stdout.join("\n"))
+        )?;
+    }
+    if !command.stderr.is_empty() {
+        writeln!(
+            writer,
+            "      <system-err>{}</system-err>",
+            escape(&command.stderr.join("\n"))
+        )?;
+    }
+
+    writeln!(writer, "    </testcase>")?;
+    Ok(())
+}
+
+/// The `tests`, `failures`, `errors`, and `skipped` attributes for the given
+/// commands.
+fn counts<'a, I>(commands: I) -> String
+where
+    I: IntoIterator<Item = &'a CommandReport>,
+{
+    let (mut tests, mut failures, mut errors, mut skipped) = (0, 0, 0, 0);
+    for command in commands {
+        tests += 1;
+        match command.outcome {
+            Outcome::Succeeded => {}
+            Outcome::Failed if command.exit_code.is_some() => failures += 1,
+            Outcome::Failed | Outcome::TimedOut => errors += 1,
+            Outcome::Skipped => skipped += 1,
+        }
+    }
+    format!(
+        r#"tests="{}" failures="{}" errors="{}" skipped="{}""#,
+        tests, failures, errors, skipped
+    )
+}
+
+/// The total time spent running commands on a host.
+fn duration(host_report: &HostReport) -> Duration {
+    host_report
+        .commands
+        .iter()
+        .filter_map(CommandReport::duration)
+        .sum()
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
+
+/// The first line of an error, skipping the generic `libmussh error` header.
+fn first_line(error: &str) -> &str {
+    error
+        .lines()
+        .find(|line| !line.is_empty() && *line != "libmussh error")
+        .unwrap_or(error)
+}
+
+/// Escape text for use in XML attributes and content, dropping the control
+/// characters XML 1.0 cannot represent.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' | '\r' | '\t' => escaped.push(ch),
+            ch if ch.is_control() => {}
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::escape;
+    use crate::config::{Command, Host};
+    use crate::error::MusshResult;
+    use crate::ssh::Multiplex;
+    use crate::utils::CmdType;
+    use indexmap::{IndexMap, IndexSet};
+
+    fn command(cmd: &str) -> Command {
+        let mut command = Command::default();
+        let _ = command.set_command(cmd.to_string());
+        command
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            escape("a < b && \"c\"\u{1b}"),
+            "a &lt; b &amp;&amp; &quot;c&quot;"
+        );
+    }
+
+    #[test]
+    fn junit() -> MusshResult<()> {
+        let mut host = Host::default();
+        let _ = host.set_hostname("localhost".to_string());
+        let mut cmds = IndexMap::new();
+        let _ = cmds.insert("ok".to_string(), command("echo '<ok>'"));
+        let _ = cmds.insert(
+            "fail".to_string(),
+            command("echo oops >&2; sleep 0.2; exit 3"),
+        );
+        let mut cmd_map = IndexMap::new();
+        let _ = cmd_map.insert(CmdType::Cmd, cmds);
+        let mut hosts_map = IndexMap::new();
+        let _ = hosts_map.insert("m1".to_string(), (host, cmd_map));
+
+        let report = Multiplex::default().run(&IndexSet::new(), hosts_map);
+        let mut buf = Vec::new();
+        report.write_junit(&mut buf)?;
+        let xml = String::from_utf8_lossy(&buf);
+
+        assert!(xml
+            .contains(r#"<testsuites name="mussh" tests="2" failures="1" errors="0" skipped="0""#));
+        assert!(xml.contains(r#"<testsuite name="m1" tests="2" failures="1""#));
+        assert!(xml.contains(r#"<testcase name="ok" classname="m1.cmd""#));
+        assert!(xml.contains("<system-out>&lt;ok&gt;</system-out>"));
+        assert!(xml.contains(r#"<failure message="exit code 3" type="non-zero">oops</failure>"#));
+        // A failed command has no metrics, but its time is still known
+        assert!(xml.contains(r#"<testcase name="fail" classname="m1.cmd" time="0."#));
+        assert!(!xml.contains(r#"<testcase name="fail" classname="m1.cmd" time="0.000""#));
+        assert!(!xml.contains(
+            r#"<testsuite name="m1" tests="2" failures="1" errors="0" skipped="0" time="0.0"#
+        ));
+        assert!(xml.trim_end().ends_with("</testsuites>"));
+        Ok(())
+    }
+
+    #[test]
+    fn junit_skipped() -> MusshResult<()> {
+        let mut host = Host::default();
+        let _ = host.set_hostname("localhost".to_string());
+        let mut cmds = IndexMap::new();
+        let _ = cmds.insert("ok".to_string(), command("true"));
+        let mut cmd_map = IndexMap::new();
+        let _ = cmd_map.insert(CmdType::SyncCmd, cmds);
+        let mut hosts_map = IndexMap::new();
+        let _ = hosts_map.insert("m1".to_string(), (host, cmd_map));
+
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.set_dry_run(true);
+        let mut buf = Vec::new();
+        multiplex
+            .run(&IndexSet::new(), hosts_map)
+            .write_junit(&mut buf)?;
+        let xml = String::from_utf8_lossy(&buf);
+
+        assert!(xml.contains(r#"<testcase name="ok" classname="m1.sync_cmd" time="0.000">"#));
+        assert!(xml.contains("<skipped/>"));
+        Ok(())
+    }
+}
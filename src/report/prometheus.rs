@@ -0,0 +1,196 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Prometheus text exposition output
+use super::{CommandReport, Outcome, RunReport};
+use crate::error::MusshResult;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+impl RunReport {
+    /// Write the report in the Prometheus text exposition format.  Every
+    /// command that was run is labelled by `host`, `cmd`, and `cmd_type`;
+    /// skipped commands are left out.
+    ///
+    /// # Errors
+    /// * The report could not be written.
+    pub fn write_prometheus<W: Write>(&self, mut writer: W) -> MusshResult<()> {
+        self.write_family(
+            &mut writer,
+            "mussh_command_duration_seconds",
+            "How long the command took to run, NaN if that is not known.",
+            |command| {
+                // A failure keeps its sample, so the series does not end
+                // exactly when the command fails
+                Some(command.duration().map_or_else(
+                    || "NaN".to_string(),
+                    |duration| format!("{}", duration.as_secs_f64()),
+                ))
+            },
+        )?;
+        self.write_family(
+            &mut writer,
+            "mussh_command_succeeded",
+            "1 if the command succeeded, 0 otherwise.",
+            |command| Some(flag(command.outcome == Outcome::Succeeded)),
+        )?;
+        self.write_family(
+            &mut writer,
+            "mussh_command_failed",
+            "1 if the command failed or timed out, 0 otherwise.",
+            |command| {
+                Some(flag(
+                    command.outcome == Outcome::Failed || command.outcome == Outcome::TimedOut,
+                ))
+            },
+        )?;
+        self.write_family(
+            &mut writer,
+            "mussh_command_last_run_timestamp_seconds",
+            "When the command last finished, in seconds since the epoch.",
+            |command| {
+                command
+                    .finished
+                    .map(|millis| format!("{}.{:03}", millis / 1000, millis % 1000))
+            },
+        )
+    }
+
+    /// Write the report to a `node_exporter` textfile collector file, such as
+    /// `/var/lib/node_exporter/mussh.prom`.
+    ///
+    /// The metrics are written to a temporary file alongside it, which is
+    /// then renamed into place, so the collector never reads a partial file.
+    ///
+    /// # Errors
+    /// * The temporary file could not be written, or renamed into place.
+    pub fn write_textfile<P: AsRef<Path>>(&self, path: P) -> MusshResult<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        self.write_prometheus(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Write a gauge family, with a sample for every command that was run
+    /// and has a value.
+    fn write_family<W, F>(
+        &self,
+        writer: &mut W,
+        name: &str,
+        help: &str,
+        value: F,
+    ) -> MusshResult<()>
+    where
+        W: Write,
+        F: Fn(&CommandReport) -> Option<String>,
+    {
+        writeln!(writer, "# HELP {} {}", name, help)?;
+        writeln!(writer, "# TYPE {} gauge", name)?;
+
+        for (hostname, host_report) in &self.hosts {
+            for command in &host_report.commands {
+                if command.outcome == Outcome::Skipped {
+                    continue;
+                }
+
+                if let Some(value) = value(command) {
+                    writeln!(
+                        writer,
+                        r#"{}{{host="{}",cmd="{}",cmd_type="{}"}} {}"#,
+                        name,
+                        escape(hostname),
+                        escape(&command.cmd_name),
+                        command.cmd_type,
+                        value
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn flag(value: bool) -> String {
+    if value { "1" } else { "0" }.to_string()
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::escape;
+    use crate::config::{Command, Host};
+    use crate::error::MusshResult;
+    use crate::ssh::Multiplex;
+    use crate::utils::CmdType;
+    use indexmap::{IndexMap, IndexSet};
+    use std::env;
+    use std::fs;
+
+    fn command(cmd: &str) -> Command {
+        let mut command = Command::default();
+        let _ = command.set_command(cmd.to_string());
+        command
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn textfile() -> MusshResult<()> {
+        let mut host = Host::default();
+        let _ = host.set_hostname("localhost".to_string());
+        let mut cmds = IndexMap::new();
+        let _ = cmds.insert("ok".to_string(), command("true"));
+        let _ = cmds.insert("fail".to_string(), command("false"));
+        let mut cmd_map = IndexMap::new();
+        let _ = cmd_map.insert(CmdType::Cmd, cmds);
+        let mut hosts_map = IndexMap::new();
+        let _ = hosts_map.insert("m1".to_string(), (host, cmd_map));
+        let report = Multiplex::default().run(&IndexSet::new(), hosts_map);
+
+        let dir = env::temp_dir().join(format!("mussh-prom-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let path = dir.join("mussh.prom");
+        report.write_textfile(&path)?;
+        let text = fs::read_to_string(&path)?;
+        let entries = fs::read_dir(&dir)?.count();
+        fs::remove_dir_all(&dir)?;
+
+        assert_eq!(entries, 1);
+        assert!(text.contains("# TYPE mussh_command_duration_seconds gauge"));
+        assert!(
+            text.contains(r#"mussh_command_duration_seconds{host="m1",cmd="ok",cmd_type="cmd"} "#)
+        );
+        assert!(text
+            .contains(r#"mussh_command_duration_seconds{host="m1",cmd="fail",cmd_type="cmd"} "#));
+        assert!(!text.contains("} NaN"));
+        assert!(text.contains(r#"mussh_command_succeeded{host="m1",cmd="ok",cmd_type="cmd"} 1"#));
+        assert!(text.contains(r#"mussh_command_failed{host="m1",cmd="fail",cmd_type="cmd"} 1"#));
+        assert!(text.contains(
+            r#"mussh_command_last_run_timestamp_seconds{host="m1",cmd="fail",cmd_type="cmd"} "#
+        ));
+        Ok(())
+    }
+}
@@ -7,34 +7,59 @@
 // modified, or distributed except according to those terms.
 
 //! Multiplex commands over hosts.
-use crate::config::Host;
-use crate::error::{MusshErrKind, MusshResult};
-use crate::utils::{convert_duration, CmdType, MultiplexMapType};
+use crate::auth::{self, AuthProvider};
+use crate::config::{
+    AuthMethod, Become, Command, DirSync, Fetch, Host, RetryPolicy, Stdin, Upload,
+};
+use crate::error::{MusshErr, MusshErrKind, MusshResult};
+use crate::event::{Event, Observer, OutputStream};
+use crate::privilege::{self, Password, PromptWatcher, Step};
+use crate::transfer::{self, Fetched, Synced};
+use crate::utils::{
+    check_env_name, convert_duration, expand_path, export, in_dir, quote_words, shell_quote,
+    split_words, CmdType, MultiplexMapType,
+};
 use chrono::Utc;
 use getset::{Getters, Setters};
 use indexmap::{IndexMap, IndexSet};
-use slog::{error, info, trace, Logger};
-use slog_try::{try_error, try_info, try_trace};
-use ssh2::Session;
-use std::collections::HashMap;
+use serde_derive::{Deserialize, Serialize};
+use slog::{error, info, trace, warn, Logger};
+use slog_try::{try_error, try_info, try_trace, try_warn};
+use ssh2::{Channel, Session};
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::io::{BufRead, BufReader};
-use std::net::TcpStream;
-use std::path::Path;
-use std::process::{Command, Stdio};
-use std::sync::mpsc::{self, Receiver};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::iter;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::{self, ChildStderr, ChildStdin, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 use wait_group::WaitGroup;
 
 type MultiplexResult = Vec<MusshResult<Metrics>>;
 
+/// The shell local commands run under when `$SHELL` is not set.
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+/// How long to wait for more output from a remote command when none is
+/// waiting to be read.
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// The most input read ahead of what a remote command has taken.
+const INPUT_CHUNK: usize = 32 * 1024;
+
 /// Execution metrics
-#[derive(Clone, Debug, Eq, Getters, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Eq, Getters, PartialEq, Serialize)]
 pub struct Metrics {
     /// The hostname where the command was run
     #[get = "pub"]
     hostname: String,
+    /// The address that was connected to
+    #[get = "pub"]
+    address: String,
     /// The name of the command that was run
     #[get = "pub"]
     cmd_name: String,
@@ -44,15 +69,25 @@ pub struct Metrics {
     /// The timestamp when this metric was created
     #[get = "pub"]
     timestamp: i64,
+    /// The number of attempts made, counting connection retries and re-runs
+    #[get = "pub"]
+    attempts: u32,
+    /// The number of bytes transferred, for uploads
+    #[get = "pub"]
+    #[serde(default)]
+    bytes: u64,
 }
 
 impl Default for Metrics {
     fn default() -> Self {
         Self {
             hostname: String::new(),
+            address: String::new(),
             cmd_name: String::new(),
             duration: Duration::new(0, 0),
             timestamp: 0,
+            attempts: 1,
+            bytes: 0,
         }
     }
 }
@@ -80,6 +115,22 @@ pub struct Multiplex {
     #[get = "pub"]
     #[set = "pub"]
     host_loggers: HashMap<String, Option<Logger>>,
+    /// Supplies passwords, key passphrases, and keyboard-interactive answers
+    #[get = "pub"]
+    #[set = "pub"]
+    auth_provider: Option<Arc<dyn AuthProvider>>,
+    /// The retry policy for hosts that do not configure their own
+    #[get = "pub"]
+    #[set = "pub"]
+    retry: RetryPolicy,
+    /// Run a preflight check first, and drop the hosts that fail it?
+    #[get = "pub"]
+    #[set = "pub"]
+    skip_unreachable: bool,
+    /// The observers sent every event emitted while multiplexing
+    #[get = "pub"]
+    #[set = "pub"]
+    observers: Vec<Arc<dyn Observer>>,
 }
 
 impl Multiplex {
@@ -90,6 +141,11 @@ impl Multiplex {
         sync_hosts: &IndexSet<String>,
         hosts_map: MultiplexMapType,
     ) -> MultiplexResult {
+        let hosts_map = if self.skip_unreachable && !self.dry_run {
+            self.drop_unreachable(hosts_map)
+        } else {
+            hosts_map
+        };
         let wg = WaitGroup::new();
         let (tx, rx) = mpsc::channel();
         let count = hosts_map.len();
@@ -118,102 +174,443 @@ impl Multiplex {
             if !self.dry_run {
                 // Setup the clones to move into the thread
                 let wg_cl = wg.clone();
-                let tx_cl = tx.clone();
-                let h_cl = host.clone();
-                let stdout_cl = self.stdout.clone();
-                let stderr_cl = self.stderr.clone();
-                let cmd_cl = self.host_loggers.get(&hostname).unwrap_or(&None).clone();
+                let mut worker = self.worker(&hostname, host);
+                worker.events = Some(tx.clone());
 
                 // The worker thread that will run the commands on the host
                 let _ = thread::spawn(move || {
-                    let mut results = execute(&stdout_cl, &stderr_cl, &cmd_cl, &h_cl, &pre_cmds);
+                    worker.execute(CmdType::Cmd, &pre_cmds);
 
                     if sync_host {
-                        results.extend(execute(&stdout_cl, &stderr_cl, &cmd_cl, &h_cl, &sync_cmds));
+                        worker.execute(CmdType::SyncCmd, &sync_cmds);
                         wg_cl.done();
+                        worker.emit(Event::PhaseBarrierReached {
+                            host: worker.name.clone(),
+                        });
                     } else {
                         wg_cl.wait();
-                        results.extend(execute(&stdout_cl, &stderr_cl, &cmd_cl, &h_cl, &sync_cmds));
+                        worker.emit(Event::PhaseBarrierReached {
+                            host: worker.name.clone(),
+                        });
+                        worker.execute(CmdType::SyncCmd, &sync_cmds);
                     }
-                    tx_cl.send(results).expect("unable to send response");
+                    worker.clean_up();
+                    worker.emit(Event::HostFinished {
+                        host: worker.name.clone(),
+                    });
                 });
 
                 if self.synchronous {
-                    self.receive(&rx, &mut results);
+                    self.receive(&rx, &mut results, 1);
                 }
             }
         }
 
         if !self.dry_run && !self.synchronous {
             // Wait for all the threads to finish
-            for _ in 0..count {
-                self.receive(&rx, &mut results);
-            }
+            self.receive(&rx, &mut results, count);
         }
 
         results
     }
 
-    fn receive(&self, rx: &Receiver<MultiplexResult>, output: &mut Vec<MusshResult<Metrics>>) {
-        match rx.recv() {
-            Ok(results) => output.extend(results),
-            Err(e) => try_error!(self.stderr, "{}", e),
+    /// Add an observer that will be sent every event emitted while
+    /// multiplexing.
+    pub fn add_observer(&mut self, observer: Arc<dyn Observer>) -> &mut Self {
+        self.observers.push(observer);
+        self
+    }
+
+    crate fn worker(&self, hostname: &str, host: Host) -> Worker {
+        Worker {
+            name: hostname.to_string(),
+            stdout: self.stdout.clone(),
+            stderr: self.stderr.clone(),
+            cmd_logger: self.host_loggers.get(hostname).unwrap_or(&None).clone(),
+            auth: self.auth_provider.clone(),
+            retry: host.retry().unwrap_or(self.retry),
+            events: None,
+            scripts: Arc::default(),
+            cmd_type: CmdType::Cmd,
+            host,
         }
     }
+
+    /// Pass events on to the observers until `hosts` hosts have finished,
+    /// collecting each host's command results once it has finished.
+    fn receive(&self, rx: &Receiver<Event>, output: &mut Vec<MusshResult<Metrics>>, hosts: usize) {
+        let mut pending: HashMap<String, MultiplexResult> = HashMap::new();
+        let mut finished = 0;
+
+        while finished < hosts {
+            match rx.recv() {
+                Ok(event) => {
+                    for observer in &self.observers {
+                        observer.on_event(&event);
+                    }
+
+                    match event {
+                        Event::CommandFinished { host, result, .. } => {
+                            pending.entry(host).or_default().push(result);
+                        }
+                        Event::HostFinished { host } => {
+                            output.extend(pending.remove(&host).unwrap_or_default());
+                            finished += 1;
+                        }
+                        _ => {}
+                    }
+                }
+                Err(e) => {
+                    try_error!(self.stderr, "{}", e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Everything a worker thread needs to run commands on a single host.
+#[derive(Clone, Debug)]
+crate struct Worker {
+    name: String,
+    stdout: Option<Logger>,
+    stderr: Option<Logger>,
+    cmd_logger: Option<Logger>,
+    auth: Option<Arc<dyn AuthProvider>>,
+    retry: RetryPolicy,
+    events: Option<Sender<Event>>,
+    /// The scripts staged on the host during this run
+    scripts: Arc<Mutex<Staging>>,
+    /// The phase the commands being run are in
+    cmd_type: CmdType,
+    crate host: Host,
 }
 
-fn execute(
-    stdout: &Option<Logger>,
-    stderr: &Option<Logger>,
-    cmd_logger: &Option<Logger>,
-    host: &Host,
-    cmds: &IndexMap<String, String>,
-) -> MultiplexResult {
-    cmds.iter()
-        .map(|(cmd_name, cmd)| execute_on_host(stdout, stderr, cmd_logger, host, cmd_name, cmd))
-        .collect()
+/// The private directory scripts are staged in on a host, created by the
+/// first script run there, and the paths of the scripts staged in it.
+#[derive(Debug, Default)]
+struct Staging {
+    dir: Option<String>,
+    scripts: IndexSet<String>,
 }
 
-fn execute_on_host(
-    stdout: &Option<Logger>,
-    stderr: &Option<Logger>,
-    cmd_logger: &Option<Logger>,
-    host: &Host,
-    cmd_name: &str,
-    cmd: &str,
-) -> MusshResult<Metrics> {
-    if host.hostname() == "localhost" {
-        execute_on_localhost(stdout, stderr, cmd_logger, host, cmd_name, cmd)
-    } else {
-        execute_on_remote(stdout, stderr, cmd_logger, host, cmd_name, cmd)
-    }
+/// An authenticated ssh session.
+crate struct Connection {
+    crate sess: Session,
+    /// The address that was connected to
+    crate address: String,
+    /// The authentication method that succeeded
+    crate method: AuthMethod,
+    /// The time taken to connect and handshake
+    crate latency: Duration,
+    /// The number of attempts made
+    crate attempts: u32,
 }
 
-fn execute_on_localhost(
-    stdout: &Option<Logger>,
-    stderr: &Option<Logger>,
-    cmd_logger: &Option<Logger>,
-    host: &Host,
-    cmd_name: &str,
-    cmd: &str,
-) -> MusshResult<Metrics> {
-    if let Some(shell_path) = env::var_os("SHELL") {
+impl Worker {
+    fn emit(&self, event: Event) {
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    fn execute(&mut self, cmd_type: CmdType, cmds: &IndexMap<String, Command>) {
+        self.cmd_type = cmd_type;
+        for (cmd_name, cmd) in cmds {
+            self.emit(Event::CommandStarted {
+                host: self.name.clone(),
+                cmd_name: cmd_name.clone(),
+                cmd_type,
+            });
+            let result = self.execute_cmd(cmd_name, cmd);
+            self.emit(Event::CommandFinished {
+                host: self.name.clone(),
+                cmd_name: cmd_name.clone(),
+                cmd_type,
+                result,
+            });
+        }
+    }
+
+    /// Run the command, re-running it on a non-zero exit if it has opted in.
+    fn execute_cmd(&self, cmd_name: &str, cmd: &Command) -> MusshResult<Metrics> {
+        let retry_on_failure = cmd.retry_on_failure().unwrap_or(false);
+        let mut attempts = 0;
+        let mut run = 1;
+
+        loop {
+            let (run_attempts, result) = self.execute_on_host(cmd_name, cmd);
+            attempts += run_attempts;
+
+            match result {
+                Ok(mut metrics) => {
+                    metrics.attempts = attempts;
+                    return Ok(metrics);
+                }
+                Err(e) if retry_on_failure && e.is_non_zero() && run < *self.retry.attempts() => {
+                    let delay = self.retry.delay(run);
+                    try_trace!(
+                        self.stdout,
+                        "execute";
+                        "message" => "Retrying",
+                        "host" => self.host.hostname(),
+                        "cmd" => cmd_name,
+                        "delay" => convert_duration(&delay)
+                    );
+                    thread::sleep(delay);
+                    run += 1;
+                }
+                Err(e) if attempts > 1 => {
+                    return Err(MusshErrKind::Retries(attempts, Box::new(e)).into())
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn execute_on_host(&self, cmd_name: &str, cmd: &Command) -> (u32, MusshResult<Metrics>) {
+        self.emit(Event::HostConnecting {
+            host: self.name.clone(),
+        });
+
+        if self.host.is_local() {
+            self.emit(Event::HostConnected {
+                host: self.name.clone(),
+                address: self.host.hostname().clone(),
+                attempts: 1,
+            });
+            let result = if let Some(upload) = cmd.upload() {
+                self.upload_on_localhost(cmd_name, upload)
+            } else if let Some(fetch) = cmd.fetch() {
+                self.fetch_on_localhost(cmd_name, fetch)
+            } else if let Some(sync) = cmd.sync() {
+                self.sync_on_localhost(cmd_name, sync)
+            } else if let Some(script) = cmd.script() {
+                self.stage_script(None, cmd, script)
+                    .and_then(|line| self.execute_on_localhost(cmd_name, &line, cmd))
+            } else {
+                self.execute_on_localhost(cmd_name, cmd.command(), cmd)
+            };
+            (1, result)
+        } else {
+            match self.open_session() {
+                Ok(conn) => {
+                    self.emit(Event::HostConnected {
+                        host: self.name.clone(),
+                        address: conn.address.clone(),
+                        attempts: conn.attempts,
+                    });
+                    let attempts = conn.attempts;
+                    let result = if let Some(upload) = cmd.upload() {
+                        self.upload_on_remote(&conn.sess, conn.address, cmd_name, upload)
+                    } else if let Some(fetch) = cmd.fetch() {
+                        self.fetch_on_remote(&conn.sess, conn.address, cmd_name, fetch)
+                    } else if let Some(sync) = cmd.sync() {
+                        self.sync_on_remote(&conn.sess, conn.address, cmd_name, sync)
+                    } else if let Some(script) = cmd.script() {
+                        self.stage_script(Some(&conn.sess), cmd, script)
+                            .and_then(|line| {
+                                self.execute_on_remote(
+                                    &conn.sess,
+                                    conn.address,
+                                    cmd_name,
+                                    &line,
+                                    cmd,
+                                )
+                            })
+                    } else {
+                        self.execute_on_remote(
+                            &conn.sess,
+                            conn.address,
+                            cmd_name,
+                            cmd.command(),
+                            cmd,
+                        )
+                    };
+                    (attempts, result)
+                }
+                Err((attempts, e)) => (attempts, Err(e)),
+            }
+        }
+    }
+
+    /// Read the output of a command line by line, logging stdout to the
+    /// command logger, and emitting every line as an event.
+    fn forward<R: Read>(&self, cmd_name: &str, stream: OutputStream, reader: R) {
+        for line in BufReader::new(reader).lines() {
+            if let Ok(line) = line {
+                self.output_line(cmd_name, stream, line);
+            }
+        }
+    }
+
+    /// Write the input of a remote command and read its output from stdout
+    /// and stderr, all together, as the channel allows.  The streams share
+    /// the channel window, so finishing any one of them first stalls a
+    /// command that fills the window with another, such as `cat`.
+    fn forward_remote(
+        &self,
+        sess: &Session,
+        channel: &mut Channel,
+        cmd_name: &str,
+        input: Option<Box<dyn Read + Send>>,
+        pty: bool,
+        held: &[u8],
+    ) -> MusshResult<()> {
+        let mut stdout = Lines::new(OutputStream::Stdout);
+        let mut stderr = Lines::new(OutputStream::Stderr);
+        // A pseudo-terminal has no separate stderr
+        let held_lines = if pty { &mut stdout } else { &mut stderr };
+        let held_stream = held_lines.stream;
+        held_lines.push(held, |line| self.output_line(cmd_name, held_stream, line));
+
+        sess.set_blocking(false);
+        let mut input = Input::new(input);
+        let result = self.pump(channel, cmd_name, &mut input, &mut stdout, &mut stderr);
+        sess.set_blocking(true);
+        result
+    }
+
+    fn pump<S: Streams>(
+        &self,
+        channel: &mut S,
+        cmd_name: &str,
+        input: &mut Input,
+        stdout: &mut Lines,
+        stderr: &mut Lines,
+    ) -> MusshResult<()> {
+        let mut chunk = [0; 8192];
+
+        while !(stdout.done && stderr.done) {
+            let mut idle = true;
+            if !input.closed {
+                match input.pump(channel) {
+                    Ok(progress) => idle &= !progress,
+                    // A command that exits without reading all of its input
+                    // still reports its own exit status.
+                    Err(e) => {
+                        try_trace!(
+                            self.stdout,
+                            "execute";
+                            "message" => "Input not fully written",
+                            "host" => self.host.hostname(),
+                            "cmd" => cmd_name,
+                            "error" => e.to_string()
+                        );
+                        input.abandon();
+                    }
+                }
+            }
+            for (stream_id, lines) in &mut [(0, &mut *stdout), (1, &mut *stderr)] {
+                if lines.done {
+                    continue;
+                }
+                let stream = lines.stream;
+                match channel.read_stream(*stream_id, &mut chunk) {
+                    Ok(0) => {
+                        idle = false;
+                        lines.finish(|line| self.output_line(cmd_name, stream, line));
+                    }
+                    Ok(read) => {
+                        idle = false;
+                        lines.push(&chunk[..read], |line| {
+                            self.output_line(cmd_name, stream, line);
+                        });
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            if idle {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+        Ok(())
+    }
+
+    /// Log a line of output, and emit it as an event.
+    fn output_line(&self, cmd_name: &str, stream: OutputStream, line: String) {
+        if stream == OutputStream::Stdout {
+            try_trace!(self.cmd_logger, "{}", line);
+        }
+        self.emit(Event::OutputLine {
+            host: self.name.clone(),
+            cmd_name: cmd_name.to_string(),
+            cmd_type: self.cmd_type,
+            stream,
+            line,
+            timestamp: Utc::now().timestamp_millis(),
+        });
+    }
+
+    fn execute_on_localhost(
+        &self,
+        cmd_name: &str,
+        cmd: &str,
+        options: &Command,
+    ) -> MusshResult<Metrics> {
+        let host = &self.host;
+
+        let input = options.stdin().as_ref().map(Stdin::reader).transpose()?;
+        let env = options.env().clone().unwrap_or_default();
+        let shell = self.shell(options);
+        let cmd = exec_style(shell, cmd)?;
+        // The user a command becomes does not inherit this environment
+        let (line, watcher) = if options.escalation().is_some() {
+            self.escalate(&export(&env, &cmd)?, options)
+        } else {
+            for name in env.keys() {
+                check_env_name(name)?;
+            }
+            self.escalate(&cmd, options)
+        };
+        // Without a terminal, the password could only go to a stdin that su
+        // and doas never read
+        if watcher.as_ref().map_or(false, PromptWatcher::needs_pty) {
+            return Err(MusshErrKind::NoTerminal(cmd_name.to_string()).into());
+        }
+        let words = local_argv(shell, line)?;
+        let (program, args) = words.split_first().ok_or_else(|| "Empty command line")?;
+        let mut command = process::Command::new(program);
+        let _ = command.args(args);
+        if options.escalation().is_none() {
+            let _ = command.envs(&env);
+        }
+        if let Some(cwd) = options.cwd() {
+            let _ = command.current_dir(expand_path(cwd));
+        }
         let timer = Instant::now();
-        let fish = shell_path.to_string_lossy().to_string();
-        let mut command = Command::new(&fish);
-        let _ = command.arg("-c");
-        let _ = command.arg(cmd);
         let _ = command.stdout(Stdio::piped());
         let _ = command.stderr(Stdio::piped());
+        if input.is_some() || watcher.is_some() {
+            let _ = command.stdin(Stdio::piped());
+        }
 
         if let Ok(mut child) = command.spawn() {
+            let child_stdin = child.stdin.take();
             let child_stdout = child.stdout.take().ok_or_else(|| "Unable to get stdout")?;
-            let stdout_reader = BufReader::new(child_stdout);
-            for line in stdout_reader.lines() {
-                if let Ok(line) = line {
-                    try_trace!(cmd_logger, "{}", line);
+            let child_stderr = child.stderr.take().ok_or_else(|| "Unable to get stderr")?;
+            let stderr_worker = self.clone();
+            let stderr_cmd_name = cmd_name.to_string();
+            let stderr_handle = thread::spawn(move || {
+                if let Some(watcher) = watcher {
+                    stderr_worker.watch_local(
+                        &stderr_cmd_name,
+                        child_stderr,
+                        child_stdin,
+                        watcher,
+                        input,
+                    );
+                } else {
+                    let stdin_handle = feed(input, child_stdin);
+                    stderr_worker.forward(&stderr_cmd_name, OutputStream::Stderr, child_stderr);
+                    if let Some(stdin_handle) = stdin_handle {
+                        let _ = stdin_handle.join();
+                    }
                 }
-            }
+            });
+            self.forward(cmd_name, OutputStream::Stdout, child_stdout);
+            let _ = stderr_handle.join();
 
             let status = child.wait()?;
             let duration = timer.elapsed();
@@ -222,12 +619,13 @@ fn execute_on_localhost(
 
             if status.success() {
                 let mut metrics = Metrics::default();
+                metrics.address = hostname.clone();
                 metrics.hostname = hostname;
                 metrics.cmd_name = cmd_name.to_string();
                 metrics.duration = duration;
                 metrics.timestamp = Utc::now().timestamp_millis();
                 try_info!(
-                    stdout,
+                    self.stdout,
                     "execute";
                     "host" => host.hostname(),
                     "cmd" => cmd_name,
@@ -236,115 +634,736 @@ fn execute_on_localhost(
                 Ok(metrics)
             } else {
                 try_error!(
-                    stderr,
+                    self.stderr,
                     "execute";
                     "host" => host.hostname(),
                     "cmd" => cmd_name,
                     "duration" => elapsed_str
                 );
-                let err_msg = format!("Failed to run '{}' on '{}'", hostname, cmd_name);
-                Err(MusshErrKind::NonZero(err_msg).into())
+                let err_msg = format!("Failed to run '{}' on '{}'", cmd_name, hostname);
+                Err(MusshErrKind::NonZero(err_msg, status.code()).into())
             }
         } else {
             Err(MusshErrKind::Spawn.into())
         }
-    } else {
-        Err(MusshErrKind::ShellNotFound.into())
     }
-}
 
-fn execute_on_remote(
-    stdout: &Option<Logger>,
-    stderr: &Option<Logger>,
-    cmd_logger: &Option<Logger>,
-    host: &Host,
-    cmd_name: &str,
-    cmd: &str,
-) -> MusshResult<Metrics> {
-    if let Ok(mut sess) = Session::new() {
-        let timer = Instant::now();
-        let host_tuple = (&host.hostname()[..], host.port().unwrap_or_else(|| 22));
-        let tcp = TcpStream::connect(host_tuple)?;
-        sess.set_tcp_stream(tcp);
-        sess.handshake()?;
-        if let Some(pem) = host.pem() {
-            sess.userauth_pubkey_file(host.username(), None, Path::new(&pem), None)?;
-        } else {
-            sess.userauth_agent(host.username())?;
+    /// The shell the command runs with, if one is configured.
+    fn shell<'a>(&'a self, options: &'a Command) -> Option<&'a Vec<String>> {
+        options
+            .shell()
+            .as_ref()
+            .or_else(|| self.host.shell().as_ref())
+    }
+
+    /// Set the variables on the channel, returning those the server refused,
+    /// which the command line must export instead.
+    fn set_env(
+        &self,
+        channel: &mut Channel,
+        cmd_name: &str,
+        env: BTreeMap<String, String>,
+    ) -> MusshResult<BTreeMap<String, String>> {
+        let mut refused = BTreeMap::new();
+        for (name, value) in env {
+            check_env_name(&name)?;
+            if channel.setenv(&name, &value).is_err() {
+                let _ = refused.insert(name, value);
+            }
         }
+        if !refused.is_empty() {
+            try_trace!(
+                self.stdout,
+                "execute";
+                "message" => "Environment refused by the server, exporting instead",
+                "host" => self.host.hostname(),
+                "cmd" => cmd_name
+            );
+        }
+        Ok(refused)
+    }
 
-        if sess.authenticated() {
-            try_trace!(stdout, "execute"; "message" => "Authenticated");
-            let mut channel = sess.channel_session()?;
-            channel.exec(cmd)?;
+    /// The command line, wrapped to run as another user if configured, and
+    /// the watcher for its password prompt.
+    fn escalate(&self, cmd: &str, options: &Command) -> (String, Option<PromptWatcher>) {
+        match options.escalation() {
+            Some(config) => {
+                let escalation = privilege::escalate(cmd, config, self.become_password(config));
+                (escalation.line, escalation.watcher)
+            }
+            None => (cmd.to_string(), None),
+        }
+    }
 
-            {
-                let stdout_stream = channel.stream(0);
-                let stdout_reader = BufReader::new(stdout_stream);
+    fn become_password(&self, config: &Become) -> Option<Password> {
+        let user = privilege::user(config);
+        self.auth
+            .as_ref()
+            .and_then(|auth| auth.become_password(self.host.hostname(), self.host.username(), user))
+            .or_else(|| {
+                config
+                    .password_env()
+                    .as_ref()
+                    .and_then(|var| env::var(var).ok())
+            })
+            .map(Password::new)
+    }
 
-                for line in stdout_reader.lines() {
-                    if let Ok(line) = line {
-                        try_trace!(cmd_logger, "{}", line);
+    /// Answer the password prompt on the command's stderr, then feed it the
+    /// input and forward the rest of stderr.
+    fn watch_local(
+        &self,
+        cmd_name: &str,
+        mut stderr: ChildStderr,
+        mut stdin: Option<ChildStdin>,
+        mut watcher: PromptWatcher,
+        input: Option<Box<dyn Read + Send>>,
+    ) {
+        let mut chunk = [0; 1024];
+        loop {
+            let read = match stderr.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            match watcher.feed(&chunk[..read]) {
+                Step::Wait => {}
+                Step::Answer(line) => {
+                    if let Some(stdin) = stdin.as_mut() {
+                        let _ = stdin.write_all(&line);
                     }
                 }
+                // Closing stdin makes the command give up
+                Step::Refused => stdin = None,
+                Step::Ready(rest) => {
+                    let stdin_handle = feed(input, stdin);
+                    let stderr = Cursor::new(rest).chain(stderr);
+                    self.forward(cmd_name, OutputStream::Stderr, stderr);
+                    if let Some(stdin_handle) = stdin_handle {
+                        let _ = stdin_handle.join();
+                    }
+                    return;
+                }
             }
+        }
+        self.forward(cmd_name, OutputStream::Stderr, &watcher.finish()[..]);
+    }
 
-            let duration = timer.elapsed();
-            let elapsed_str = convert_duration(&duration);
+    /// Stage the script on the host, unless it already has been during this
+    /// run, returning the command line that runs it.  The first script
+    /// staged creates the host's private staging directory.
+    fn stage_script(
+        &self,
+        sess: Option<&Session>,
+        cmd: &Command,
+        script: &str,
+    ) -> MusshResult<String> {
+        let contents = fs::read(expand_path(script))?;
+        let mut staging = self
+            .scripts
+            .lock()
+            .map_err(|_| "script cache lock poisoned")?;
+        if staging.dir.is_none() {
+            staging.dir = Some(match sess {
+                Some(sess) => transfer::script_dir_remote(sess)?,
+                None => transfer::script_dir_local()?,
+            });
+        }
+        let dir = staging.dir.clone().unwrap_or_default();
+        let path = transfer::script_path(&dir, script, &contents);
 
-            match channel.exit_status() {
-                Ok(code) => {
-                    if code == 0 {
-                        let mut metrics = Metrics::default();
-                        metrics.hostname = host.hostname().to_string();
-                        metrics.cmd_name = cmd_name.to_string();
-                        metrics.duration = duration;
-                        metrics.timestamp = Utc::now().timestamp_millis();
-
-                        try_info!(
-                            stdout,
-                            "execute";
-                            "host" => host.hostname(),
-                            "cmd" => cmd_name,
-                            "duration" => elapsed_str
-                        );
-                        Ok(metrics)
-                    } else {
-                        try_error!(
-                            stderr,
-                            "execute";
-                            "host" => host.hostname(),
-                            "cmd" => cmd_name,
-                            "duration" => elapsed_str
-                        );
-                        let err_msg =
-                            format!("Failed to run '{}' on '{}'", host.hostname(), cmd_name);
-                        Err(MusshErrKind::NonZero(err_msg).into())
-                    }
+        if !staging.scripts.contains(&path) {
+            let mut upload = Upload::default();
+            let _ = upload
+                .set_source(script.to_string())
+                .set_dest(path.clone())
+                .set_mode(Some("0700".to_string()))
+                .set_atomic(Some(true));
+            let bytes = match sess {
+                Some(sess) => transfer::upload_remote(sess, &upload)?,
+                None => transfer::upload_local(&upload)?,
+            };
+            try_trace!(
+                self.stdout,
+                "execute";
+                "message" => "Staged script",
+                "host" => self.host.hostname(),
+                "path" => &path,
+                "bytes" => bytes
+            );
+            let _ = staging.scripts.insert(path.clone());
+        }
+        Ok(transfer::script_command(cmd, &path))
+    }
+
+    /// Remove the directory the scripts were staged in during this run.
+    fn clean_up(&self) {
+        let dir = match self
+            .scripts
+            .lock()
+            .ok()
+            .and_then(|mut staging| staging.dir.take())
+        {
+            Some(dir) => dir,
+            None => return,
+        };
+
+        let result = if self.host.is_local() {
+            fs::remove_dir_all(&dir).map_err(MusshErr::from)
+        } else {
+            let rm = format!("rm -rf {}", shell_quote(&dir));
+            self.open_session()
+                .map_err(|(_, e)| e)
+                .and_then(|conn| transfer::exec_remote(&conn.sess, &rm))
+        };
+
+        if let Err(e) = result {
+            try_error!(
+                self.stderr,
+                "cleanup";
+                "host" => self.host.hostname(),
+                "path" => &dir,
+                "error" => e.to_string()
+            );
+        }
+    }
+
+    fn upload_on_localhost(&self, cmd_name: &str, upload: &Upload) -> MusshResult<Metrics> {
+        let timer = Instant::now();
+        let result = transfer::upload_local(upload);
+        let address = self.host.hostname().clone();
+        let duration = timer.elapsed();
+        self.transfer_metrics("upload", cmd_name, address, upload.dest(), duration, result)
+    }
+
+    fn upload_on_remote(
+        &self,
+        sess: &Session,
+        address: String,
+        cmd_name: &str,
+        upload: &Upload,
+    ) -> MusshResult<Metrics> {
+        let timer = Instant::now();
+        let result = transfer::upload_remote(sess, upload);
+        let duration = timer.elapsed();
+        self.transfer_metrics("upload", cmd_name, address, upload.dest(), duration, result)
+    }
+
+    fn fetch_on_localhost(&self, cmd_name: &str, fetch: &Fetch) -> MusshResult<Metrics> {
+        let timer = Instant::now();
+        let result = transfer::fetch_local(&self.name, fetch);
+        let result = self.fetched(cmd_name, result);
+        let address = self.host.hostname().clone();
+        let duration = timer.elapsed();
+        self.transfer_metrics("fetch", cmd_name, address, fetch.source(), duration, result)
+    }
+
+    fn fetch_on_remote(
+        &self,
+        sess: &Session,
+        address: String,
+        cmd_name: &str,
+        fetch: &Fetch,
+    ) -> MusshResult<Metrics> {
+        let timer = Instant::now();
+        let result = transfer::fetch_remote(sess, &self.name, fetch);
+        let result = self.fetched(cmd_name, result);
+        let duration = timer.elapsed();
+        self.transfer_metrics("fetch", cmd_name, address, fetch.source(), duration, result)
+    }
+
+    /// Log the files a fetch skipped for being too large.
+    fn fetched(&self, cmd_name: &str, result: MusshResult<Fetched>) -> MusshResult<u64> {
+        result.map(|fetched| {
+            for path in &fetched.skipped {
+                try_warn!(
+                    self.stderr,
+                    "fetch";
+                    "message" => "Skipped, larger than the maximum size",
+                    "host" => self.host.hostname(),
+                    "cmd" => cmd_name,
+                    "path" => path.display().to_string()
+                );
+            }
+            fetched.bytes
+        })
+    }
+
+    fn sync_on_localhost(&self, cmd_name: &str, sync: &DirSync) -> MusshResult<Metrics> {
+        let timer = Instant::now();
+        let result = transfer::sync_local(sync);
+        let result = self.synced(cmd_name, result);
+        let address = self.host.hostname().clone();
+        let duration = timer.elapsed();
+        self.transfer_metrics("sync", cmd_name, address, sync.dest(), duration, result)
+    }
+
+    fn sync_on_remote(
+        &self,
+        sess: &Session,
+        address: String,
+        cmd_name: &str,
+        sync: &DirSync,
+    ) -> MusshResult<Metrics> {
+        let timer = Instant::now();
+        let result = transfer::sync_remote(sess, sync);
+        let result = self.synced(cmd_name, result);
+        let duration = timer.elapsed();
+        self.transfer_metrics("sync", cmd_name, address, sync.dest(), duration, result)
+    }
+
+    /// Report each change a sync made as a line of output.
+    fn synced(&self, cmd_name: &str, result: MusshResult<Synced>) -> MusshResult<u64> {
+        result.map(|synced| {
+            for change in &synced.changes {
+                self.output_line(cmd_name, OutputStream::Stdout, change.to_string());
+            }
+            synced.bytes
+        })
+    }
+
+    fn transfer_metrics(
+        &self,
+        op: &str,
+        cmd_name: &str,
+        address: String,
+        path: &str,
+        duration: Duration,
+        result: MusshResult<u64>,
+    ) -> MusshResult<Metrics> {
+        let host = &self.host;
+        let elapsed_str = convert_duration(&duration);
+
+        match result {
+            Ok(bytes) => {
+                let mut metrics = Metrics::default();
+                metrics.hostname = host.hostname().clone();
+                metrics.address = address;
+                metrics.cmd_name = cmd_name.to_string();
+                metrics.duration = duration;
+                metrics.timestamp = Utc::now().timestamp_millis();
+                metrics.bytes = bytes;
+
+                try_info!(
+                    self.stdout,
+                    "{}", op;
+                    "host" => host.hostname(),
+                    "cmd" => cmd_name,
+                    "path" => path,
+                    "bytes" => bytes,
+                    "duration" => elapsed_str
+                );
+                Ok(metrics)
+            }
+            Err(e) => {
+                try_error!(
+                    self.stderr,
+                    "{}", op;
+                    "host" => host.hostname(),
+                    "cmd" => cmd_name,
+                    "path" => path,
+                    "error" => e.to_string(),
+                    "duration" => elapsed_str
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// Connect, handshake, and authenticate, retrying transient failures
+    /// according to the retry policy.  On failure, the number of attempts
+    /// made is returned along with the last error.
+    crate fn open_session(&self) -> Result<Connection, (u32, MusshErr)> {
+        let mut attempt = 1;
+
+        loop {
+            match self.try_open_session() {
+                Ok(mut conn) => {
+                    conn.attempts = attempt;
+                    return Ok(conn);
                 }
-                Err(e) => {
+                Err(e) if attempt < *self.retry.attempts() => {
+                    let delay = self.retry.delay(attempt);
+                    try_trace!(
+                        self.stdout,
+                        "execute";
+                        "message" => "Reconnecting",
+                        "host" => self.host.hostname(),
+                        "error" => e.to_string(),
+                        "delay" => convert_duration(&delay)
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(e) => return Err((attempt, e)),
+            }
+        }
+    }
+
+    fn try_open_session(&self) -> MusshResult<Connection> {
+        let timer = Instant::now();
+        let (sess, address) = connect(&self.host)?;
+        try_trace!(self.stdout, "execute"; "message" => "Connected", "address" => &address);
+        let latency = timer.elapsed();
+        let method = auth::authenticate(&sess, &self.host, &self.auth)?;
+        try_trace!(self.stdout, "execute"; "message" => "Authenticated", "method" => method.to_string());
+        Ok(Connection {
+            sess,
+            address,
+            method,
+            latency,
+            attempts: 1,
+        })
+    }
+
+    fn execute_on_remote(
+        &self,
+        sess: &Session,
+        address: String,
+        cmd_name: &str,
+        cmd: &str,
+        options: &Command,
+    ) -> MusshResult<Metrics> {
+        let host = &self.host;
+        let input = options.stdin().as_ref().map(Stdin::reader).transpose()?;
+        let shell = self.shell(options);
+        let cmd = in_dir(options.cwd().as_ref(), &exec_style(shell, cmd)?);
+        let env = options.env().clone().unwrap_or_default();
+        let timer = Instant::now();
+        let mut channel = sess.channel_session()?;
+        // The user a command becomes does not inherit the channel's
+        // environment
+        let exported = if options.escalation().is_some() {
+            env
+        } else {
+            self.set_env(&mut channel, cmd_name, env)?
+        };
+        let (line, watcher) = self.escalate(&export(&exported, &cmd)?, options);
+        let line = remote_line(shell, line);
+        let pty = options.pty().unwrap_or(false)
+            || watcher.as_ref().map_or(false, PromptWatcher::needs_pty);
+        if pty {
+            channel.request_pty("xterm", None, None)?;
+        }
+        channel.exec(&line)?;
+
+        let (held, running) = match watcher {
+            Some(watcher) => watch_remote(&mut channel, pty, watcher)?,
+            None => (Vec::new(), true),
+        };
+
+        let input = input.filter(|_| running);
+        self.forward_remote(sess, &mut channel, cmd_name, input, pty, &held)?;
+        channel.wait_close()?;
+
+        let duration = timer.elapsed();
+        let elapsed_str = convert_duration(&duration);
+
+        match channel.exit_status() {
+            Ok(code) => {
+                if code == 0 {
+                    let mut metrics = Metrics::default();
+                    metrics.hostname = host.hostname().to_string();
+                    metrics.address = address;
+                    metrics.cmd_name = cmd_name.to_string();
+                    metrics.duration = duration;
+                    metrics.timestamp = Utc::now().timestamp_millis();
+
+                    try_info!(
+                        self.stdout,
+                        "execute";
+                        "host" => host.hostname(),
+                        "cmd" => cmd_name,
+                        "duration" => elapsed_str
+                    );
+                    Ok(metrics)
+                } else {
                     try_error!(
-                        stderr,
-                        "execute"; "hostname" => host.hostname(), "cmd" => cmd_name, "error" => format!("{}", e)
+                        self.stderr,
+                        "execute";
+                        "host" => host.hostname(),
+                        "cmd" => cmd_name,
+                        "duration" => elapsed_str
                     );
-                    let err_msg = format!("Failed to run '{}' on '{}'", host.hostname(), cmd_name);
-                    Err(MusshErrKind::SshExec(err_msg).into())
+                    let err_msg = format!("Failed to run '{}' on '{}'", cmd_name, host.hostname());
+                    Err(MusshErrKind::NonZero(err_msg, Some(code)).into())
                 }
             }
+            Err(e) => {
+                try_error!(
+                    self.stderr,
+                    "execute"; "hostname" => host.hostname(), "cmd" => cmd_name, "error" => format!("{}", e)
+                );
+                let err_msg = format!("Failed to run '{}' on '{}'", cmd_name, host.hostname());
+                Err(MusshErrKind::SshExec(err_msg).into())
+            }
+        }
+    }
+}
+
+/// The streams of a running remote command.  Every call returns
+/// `WouldBlock` rather than waiting.
+trait Streams {
+    fn read_stream(&mut self, stream_id: i32, buf: &mut [u8]) -> io::Result<usize>;
+    fn write_input(&mut self, buf: &[u8]) -> io::Result<usize>;
+    fn close_input(&mut self) -> io::Result<()>;
+}
+
+impl Streams for Channel {
+    fn read_stream(&mut self, stream_id: i32, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream(stream_id).read(buf)
+    }
+
+    fn write_input(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write(buf)
+    }
+
+    fn close_input(&mut self) -> io::Result<()> {
+        Ok(self.send_eof()?)
+    }
+}
+
+/// The input of a remote command, written a chunk at a time as the command
+/// takes it, and then closed.
+struct Input {
+    reader: Option<Box<dyn Read + Send>>,
+    chunk: Vec<u8>,
+    written: usize,
+    /// The end of file has been sent
+    closed: bool,
+}
+
+impl Input {
+    fn new(reader: Option<Box<dyn Read + Send>>) -> Self {
+        Self {
+            reader,
+            chunk: Vec::new(),
+            written: 0,
+            closed: false,
+        }
+    }
+
+    /// Write as much of the input as the channel takes, or send the end of
+    /// file once it has all been written.  Returns whether anything was
+    /// done.
+    fn pump<S: Streams>(&mut self, channel: &mut S) -> io::Result<bool> {
+        if self.written < self.chunk.len() {
+            let written = would_block(channel.write_input(&self.chunk[self.written..]))?;
+            self.written += written.unwrap_or(0);
+            Ok(written.map_or(false, |written| written > 0))
+        } else if let Some(reader) = &mut self.reader {
+            self.chunk.resize(INPUT_CHUNK, 0);
+            let read = reader.read(&mut self.chunk)?;
+            self.chunk.truncate(read);
+            self.written = 0;
+            if read == 0 {
+                self.reader = None;
+            }
+            Ok(true)
         } else {
-            Err(MusshErrKind::SshAuthentication.into())
+            self.closed = would_block(channel.close_input())?.is_some();
+            Ok(self.closed)
+        }
+    }
+
+    /// Give up on the rest of the input after an error, and on sending the
+    /// end of file if that was what failed.
+    fn abandon(&mut self) {
+        self.closed = self.reader.is_none() && self.written == self.chunk.len();
+        self.reader = None;
+        self.chunk.clear();
+        self.written = 0;
+    }
+}
+
+/// The result of a non-blocking call, with `None` if it would have blocked.
+fn would_block<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Splits the output of a stream into lines as it arrives.
+struct Lines {
+    stream: OutputStream,
+    partial: Vec<u8>,
+    done: bool,
+}
+
+impl Lines {
+    fn new(stream: OutputStream) -> Self {
+        Self {
+            stream,
+            partial: Vec::new(),
+            done: false,
+        }
+    }
+
+    /// Add the bytes read, passing on every line they complete.
+    fn push<F: FnMut(String)>(&mut self, bytes: &[u8], mut line: F) {
+        self.partial.extend_from_slice(bytes);
+        while let Some(end) = self.partial.iter().position(|b| *b == b'\n') {
+            let rest = self.partial.split_off(end + 1);
+            let mut complete = std::mem::replace(&mut self.partial, rest);
+            let _ = complete.pop();
+            if complete.last() == Some(&b'\r') {
+                let _ = complete.pop();
+            }
+            line(String::from_utf8_lossy(&complete).to_string());
+        }
+    }
+
+    /// The stream has ended, pass on the last line if it had no newline.
+    fn finish<F: FnMut(String)>(&mut self, mut line: F) {
+        self.done = true;
+        if !self.partial.is_empty() {
+            line(String::from_utf8_lossy(&self.partial).to_string());
+            self.partial.clear();
         }
-    } else {
-        Err(MusshErrKind::SshSession.into())
     }
 }
 
+/// The command line, with its words quoted so a shell runs them as they are
+/// if the command is to run without one.
+fn exec_style(shell: Option<&Vec<String>>, cmd: &str) -> MusshResult<String> {
+    match shell {
+        Some(shell) if shell.is_empty() => Ok(quote_words(&split_words(cmd)?)),
+        _ => Ok(cmd.to_string()),
+    }
+}
+
+/// The program, and its arguments, that runs the command line on this host.
+fn local_argv(shell: Option<&Vec<String>>, line: String) -> MusshResult<Vec<String>> {
+    match shell {
+        Some(shell) if shell.is_empty() => split_words(&line),
+        Some(shell) => Ok(shell.iter().cloned().chain(iter::once(line)).collect()),
+        None => {
+            let program = env::var("SHELL")
+                .ok()
+                .filter(|program| !program.is_empty())
+                .unwrap_or_else(|| DEFAULT_SHELL.to_string());
+            Ok(vec![program, "-c".to_string(), line])
+        }
+    }
+}
+
+/// The command line that runs the line under the configured shell, rather
+/// than the user's login shell, on a remote host.
+fn remote_line(shell: Option<&Vec<String>>, line: String) -> String {
+    match shell {
+        Some(shell) if !shell.is_empty() => {
+            format!("{} {}", quote_words(shell), shell_quote(&line))
+        }
+        _ => line,
+    }
+}
+
+/// Answer the password prompt on the channel, returning the output held
+/// back while watching, and whether the command is now running as the
+/// user.
+fn watch_remote(
+    channel: &mut Channel,
+    pty: bool,
+    mut watcher: PromptWatcher,
+) -> MusshResult<(Vec<u8>, bool)> {
+    let mut chunk = [0; 1024];
+    loop {
+        // A pseudo-terminal has no separate stderr
+        let read = if pty {
+            channel.read(&mut chunk)?
+        } else {
+            channel.stderr().read(&mut chunk)?
+        };
+        if read == 0 {
+            return Ok((watcher.finish(), false));
+        }
+        match watcher.feed(&chunk[..read]) {
+            Step::Wait => {}
+            Step::Answer(line) => channel.write_all(&line)?,
+            Step::Refused => return Ok((watcher.finish(), false)),
+            Step::Ready(rest) => return Ok((rest, true)),
+        }
+    }
+}
+
+/// Feed the input to a local command from its own thread, so a command that
+/// writes while it reads cannot deadlock.  Dropping stdin closes it.
+fn feed(
+    input: Option<Box<dyn Read + Send>>,
+    stdin: Option<ChildStdin>,
+) -> Option<thread::JoinHandle<()>> {
+    match (input, stdin) {
+        (Some(mut input), Some(mut stdin)) => Some(thread::spawn(move || {
+            let _ = io::copy(&mut input, &mut stdin);
+        })),
+        _ => None,
+    }
+}
+
+/// Connect to the first of the host's addresses that accepts a connection.
+fn connect(host: &Host) -> MusshResult<(Session, String)> {
+    let port = host.port().unwrap_or(22);
+    let timeout = host.connect_timeout().map(Duration::from_secs);
+    let mut failures = Vec::new();
+
+    for address in host.addresses() {
+        match connect_to(&address, port, timeout).and_then(handshake) {
+            Ok(sess) => return Ok((sess, address)),
+            Err(e) => failures.push((address, e)),
+        }
+    }
+
+    Err(MusshErrKind::SshConnect(failures).into())
+}
+
+/// Start an ssh session on the connection.  A failed handshake moves on to
+/// the next address, like a failed connection.
+fn handshake(tcp: TcpStream) -> io::Result<Session> {
+    let mut sess = Session::new()?;
+    sess.set_tcp_stream(tcp);
+    sess.handshake()?;
+    Ok(sess)
+}
+
+fn connect_to(address: &str, port: u16, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let mut last_err = None;
+
+    for addr in (address, port).to_socket_addrs()? {
+        let tcp = if let Some(timeout) = timeout {
+            TcpStream::connect_timeout(&addr, timeout)
+        } else {
+            TcpStream::connect(addr)
+        };
+
+        match tcp {
+            Ok(tcp) => return Ok(tcp),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses resolved")))
+}
+
 #[cfg(test)]
 mod tests {
-    use super::Multiplex;
+    use super::{connect, Input, Lines, Multiplex, Streams};
     use crate::config::test::test_cli;
-    use crate::config::{HostsCmds, Mussh};
+    use crate::config::{
+        Become, BecomeMethod, Command, Host, HostsCmds, Mussh, RetryPolicy, Stdin, Transport,
+    };
     use crate::error::MusshResult;
+    use crate::event::{Event, Observer, OutputStream};
+    use crate::utils::{CmdType, MultiplexMapType};
+    use indexmap::{IndexMap, IndexSet};
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs;
+    use std::io::{self, Cursor};
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
 
     crate const MUSSH_FULL_TOML: &str = r#"[hostlist.most]
 hostnames = ["m1", "m2", "m3", "m4"]
@@ -397,4 +1416,464 @@ command = "uname -a"
         let _ = multiplex.multiplex(hosts_cmds.sync_hosts(), hosts_map);
         Ok(())
     }
+
+    fn localhost_map(cmds: &[(&str, &str, bool)]) -> MultiplexMapType {
+        let mut host = Host::default();
+        let _ = host.set_hostname("localhost".to_string());
+        let mut cmd_map = IndexMap::new();
+        for (cmd_name, cmd, retry_on_failure) in cmds {
+            let mut command = Command::default();
+            let _ = command.set_command(cmd.to_string());
+            let _ = command.set_retry_on_failure(Some(*retry_on_failure));
+            let _ = cmd_map.insert(cmd_name.to_string(), command);
+        }
+        let mut cmd_type_map = IndexMap::new();
+        let _ = cmd_type_map.insert(CmdType::Cmd, cmd_map);
+        let mut hosts_map = IndexMap::new();
+        let _ = hosts_map.insert("local".to_string(), (host, cmd_type_map));
+        hosts_map
+    }
+
+    #[test]
+    fn retry_non_zero() {
+        let hosts_map = localhost_map(&[("ok", "true", false), ("fail", "false", true)]);
+        let mut retry = RetryPolicy::default();
+        let _ = retry.set_attempts(3).set_initial_delay(1);
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.set_retry(retry);
+        let results = multiplex.multiplex(&IndexSet::new(), hosts_map);
+        assert_eq!(results.len(), 2);
+        match &results[0] {
+            Ok(metrics) => assert_eq!(*metrics.attempts(), 1),
+            Err(e) => panic!("{}", e),
+        }
+        match &results[1] {
+            Ok(_) => panic!("expected failure"),
+            Err(e) => assert!(e.to_string().contains("gave up after 3 attempts")),
+        }
+    }
+
+    #[test]
+    fn no_retry_without_opt_in() {
+        let hosts_map = localhost_map(&[("fail", "false", false)]);
+        let mut retry = RetryPolicy::default();
+        let _ = retry.set_attempts(3).set_initial_delay(1);
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.set_retry(retry);
+        let results = multiplex.multiplex(&IndexSet::new(), hosts_map);
+        match &results[0] {
+            Ok(_) => panic!("expected failure"),
+            Err(e) => assert!(!e.to_string().contains("attempts")),
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl Observer for Recorder {
+        fn on_event(&self, event: &Event) {
+            let summary = match event {
+                Event::HostConnecting { host } => format!("connecting {}", host),
+                Event::HostConnected { host, .. } => format!("connected {}", host),
+                Event::CommandStarted { cmd_name, .. } => format!("started {}", cmd_name),
+                Event::OutputLine { stream, line, .. } => format!("{} {}", stream, line),
+                Event::CommandFinished {
+                    cmd_name, result, ..
+                } => format!("finished {} {}", cmd_name, result.is_ok()),
+                Event::PhaseBarrierReached { host } => format!("barrier {}", host),
+                Event::HostFinished { host } => format!("finished {}", host),
+            };
+            if let Ok(mut events) = self.events.lock() {
+                events.push(summary);
+            }
+        }
+    }
+
+    #[test]
+    fn observe_events() {
+        let hosts_map = localhost_map(&[("echo", "echo hello; echo oops >&2", false)]);
+        let recorder = Arc::new(Recorder::default());
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.add_observer(recorder.clone());
+        let results = multiplex.multiplex(&IndexSet::new(), hosts_map);
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_ok());
+
+        let events = recorder
+            .events
+            .lock()
+            .map(|x| x.clone())
+            .unwrap_or_default();
+        assert_eq!(events.len(), 8);
+        assert_eq!(
+            events[..3],
+            ["started echo", "connecting local", "connected local"]
+        );
+        // The streams are read concurrently, so only the lines of each one
+        // are in order
+        let mut output = events[3..5].to_vec();
+        output.sort();
+        assert_eq!(output, ["stderr oops", "stdout hello"]);
+        assert_eq!(
+            events[5..],
+            ["finished echo true", "barrier local", "finished local"]
+        );
+        assert_eq!(OutputStream::Stderr.to_string(), "stderr");
+    }
+
+    #[test]
+    fn local_su_with_password() {
+        let mut hosts_map = localhost_map(&[("su", "true", false)]);
+        env::set_var("MUSSH_TEST_SU_PASSWORD", "s3cret");
+        let mut config = Become::default();
+        let _ = config
+            .set_method(Some(BecomeMethod::Su))
+            .set_password_env(Some("MUSSH_TEST_SU_PASSWORD".to_string()));
+        if let Some((_, cmd_map)) = hosts_map.get_mut("local") {
+            if let Some(cmds) = cmd_map.get_mut(&CmdType::Cmd) {
+                let _ = cmds["su"].set_escalation(Some(config));
+            }
+        }
+
+        let results = Multiplex::default().multiplex(&IndexSet::new(), hosts_map);
+        match &results[0] {
+            Ok(_) => panic!("expected failure"),
+            Err(e) => assert!(e.to_string().contains("needs a terminal")),
+        }
+    }
+
+    #[test]
+    fn upload_in_a_phase() -> MusshResult<()> {
+        let source = env::temp_dir().join(format!("mussh-motd-{}", std::process::id()));
+        let dest = env::temp_dir().join(format!("mussh-motd-dest-{}", std::process::id()));
+        fs::write(&source, "hello\n")?;
+        let toml = format!(
+            r#"[hostlist.local]
+hostnames = ["local"]
+[hosts.local]
+hostname = "localhost"
+username = "jozias"
+
+[cmd.motd]
+upload = {{ source = "{}", dest = "{}", mode = "0644", atomic = true }}
+[cmd.cat]
+command = "cat {}"
+"#,
+            source.display(),
+            dest.display(),
+            dest.display()
+        );
+        let config: Mussh = toml::from_str(&toml)?;
+        let cli = vec!["test", "-h", "local", "-c", "motd,cat"];
+        let matches = test_cli().get_matches_from_safe(cli)?;
+        let hosts_map = config.to_host_map(&HostsCmds::from(&matches));
+        let results = Multiplex::default().multiplex(&IndexSet::new(), hosts_map);
+
+        assert_eq!(results.len(), 2);
+        match &results[0] {
+            Ok(metrics) => {
+                assert_eq!(metrics.cmd_name(), "motd");
+                assert_eq!(*metrics.bytes(), 6);
+            }
+            Err(e) => panic!("{}", e),
+        }
+        match &results[1] {
+            Ok(metrics) => assert_eq!(*metrics.bytes(), 0),
+            Err(e) => panic!("{}", e),
+        }
+
+        fs::remove_file(&source)?;
+        fs::remove_file(&dest)?;
+        Ok(())
+    }
+
+    #[test]
+    fn run_script() -> MusshResult<()> {
+        let script = env::temp_dir().join(format!("mussh-script-{}.sh", std::process::id()));
+        fs::write(&script, "echo \"$1 and $2\"\necho \"$0\" >&2\n")?;
+        let mut hosts_map = localhost_map(&[]);
+        for (cmd_name, args) in &[("first", "a b"), ("second", "c")] {
+            let mut command = Command::default();
+            let _ = command
+                .set_script(Some(script.to_string_lossy().to_string()))
+                .set_interpreter(Some("sh".to_string()))
+                .set_args(Some(vec![args.to_string(), "d".to_string()]));
+            if let Some((_, cmd_map)) = hosts_map.get_mut("local") {
+                if let Some(cmds) = cmd_map.get_mut(&CmdType::Cmd) {
+                    let _ = cmds.insert(cmd_name.to_string(), command);
+                }
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.add_observer(recorder.clone());
+        let results = multiplex.multiplex(&IndexSet::new(), hosts_map);
+        assert!(results.iter().all(Result::is_ok));
+
+        let events = recorder
+            .events
+            .lock()
+            .map(|x| x.clone())
+            .unwrap_or_default();
+        let output: Vec<&String> = events.iter().filter(|e| e.starts_with("stdout")).collect();
+        assert_eq!(output, vec!["stdout a b and d", "stdout c and d"]);
+        // Both run the copy staged by the first
+        let staged: Vec<&String> = events.iter().filter(|e| e.starts_with("stderr")).collect();
+        assert_eq!(staged.len(), 2);
+        assert_eq!(staged[0], staged[1]);
+        assert!(staged[0].ends_with(&format!("-mussh-script-{}.sh", std::process::id())));
+
+        // The staging directory is removed at the end of the run
+        let prefix = format!("mussh-run-{}-", std::process::id());
+        let staged = fs::read_dir(env::temp_dir())?
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .count();
+        assert_eq!(staged, 0);
+        fs::remove_file(&script)?;
+        Ok(())
+    }
+
+    #[test]
+    fn forward_stdin() -> MusshResult<()> {
+        let input = env::temp_dir().join(format!("mussh-stdin-{}", std::process::id()));
+        fs::write(&input, "from a file\n")?;
+        let mut hosts_map = localhost_map(&[
+            ("text", "cat", false),
+            ("file", "tr a-z A-Z", false),
+            ("unread", "true", false),
+        ]);
+        let stdins = vec![
+            Stdin::Text("hello\nworld\n".to_string()),
+            Stdin::File(input.to_string_lossy().to_string()),
+            Stdin::Bytes(vec![0; 1 << 20]),
+        ];
+        if let Some((_, cmd_map)) = hosts_map.get_mut("local") {
+            if let Some(cmds) = cmd_map.get_mut(&CmdType::Cmd) {
+                for (cmd, stdin) in cmds.values_mut().zip(stdins) {
+                    let _ = cmd.set_stdin(Some(stdin));
+                }
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.add_observer(recorder.clone());
+        let results = multiplex.multiplex(&IndexSet::new(), hosts_map);
+        assert!(results.iter().all(Result::is_ok));
+
+        let events = recorder
+            .events
+            .lock()
+            .map(|x| x.clone())
+            .unwrap_or_default();
+        let output: Vec<&String> = events.iter().filter(|e| e.starts_with("stdout")).collect();
+        assert_eq!(
+            output,
+            vec!["stdout hello", "stdout world", "stdout FROM A FILE"]
+        );
+
+        fs::remove_file(&input)?;
+        Ok(())
+    }
+
+    #[test]
+    fn env_and_cwd() -> MusshResult<()> {
+        let cwd = env::temp_dir().canonicalize()?;
+        let mut hosts_map = localhost_map(&[
+            ("env", "echo \"$GREETING\" from \"$(pwd)\"", false),
+            ("home", "pwd -P", false),
+            ("invalid", "true", false),
+        ]);
+        if let Some((_, cmd_map)) = hosts_map.get_mut("local") {
+            if let Some(cmds) = cmd_map.get_mut(&CmdType::Cmd) {
+                let mut greeting = BTreeMap::new();
+                let _ = greeting.insert("GREETING".to_string(), "it's me".to_string());
+                let _ = cmds["env"]
+                    .set_env(Some(greeting))
+                    .set_cwd(Some(cwd.to_string_lossy().to_string()));
+                let _ = cmds["home"].set_cwd(Some("~".to_string()));
+                let mut invalid = BTreeMap::new();
+                let _ = invalid.insert("NOT VALID".to_string(), String::new());
+                let _ = cmds["invalid"].set_env(Some(invalid));
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.add_observer(recorder.clone());
+        let results = multiplex.multiplex(&IndexSet::new(), hosts_map);
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+
+        let events = recorder
+            .events
+            .lock()
+            .map(|x| x.clone())
+            .unwrap_or_default();
+        let output: Vec<&String> = events.iter().filter(|e| e.starts_with("stdout")).collect();
+        let home = PathBuf::from(env::var("HOME").unwrap_or_default()).canonicalize()?;
+        assert_eq!(
+            output,
+            vec![
+                &format!("stdout it's me from {}", cwd.display()),
+                &format!("stdout {}", home.display())
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn configured_shell() {
+        let mut hosts_map = localhost_map(&[
+            ("exec", r#"printf '%s|' "a  b" '$HOME' $HOME"#, false),
+            ("sh", "echo $((1 + 2))", false),
+            ("unbalanced", "echo 'oops", false),
+        ]);
+        if let Some((host, cmd_map)) = hosts_map.get_mut("local") {
+            let _ = host.set_shell(Some(vec![]));
+            if let Some(cmds) = cmd_map.get_mut(&CmdType::Cmd) {
+                let _ = cmds["sh"].set_shell(Some(vec!["/bin/sh".to_string(), "-c".to_string()]));
+            }
+        }
+
+        let recorder = Arc::new(Recorder::default());
+        let mut multiplex = Multiplex::default();
+        let _ = multiplex.add_observer(recorder.clone());
+        let results = multiplex.multiplex(&IndexSet::new(), hosts_map);
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+
+        let events = recorder
+            .events
+            .lock()
+            .map(|x| x.clone())
+            .unwrap_or_default();
+        let output: Vec<&String> = events.iter().filter(|e| e.starts_with("stdout")).collect();
+        assert_eq!(output, vec!["stdout a  b|$HOME|$HOME|", "stdout 3"]);
+    }
+
+    #[test]
+    fn detect_local() {
+        let mut hosts_map = localhost_map(&[("pwd", "pwd", false)]);
+        if let Some((host, _)) = hosts_map.get_mut("local") {
+            let _ = host
+                .set_hostname("127.0.0.1".to_string())
+                .set_transport(Some(Transport::Auto));
+        }
+        let results = Multiplex::default().multiplex(&IndexSet::new(), hosts_map);
+        assert_eq!(results.len(), 1);
+        assert!(results.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn fail_over_on_handshake() -> MusshResult<()> {
+        // Accepts connections, then closes them without a handshake
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let handle = std::thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                drop(stream);
+            }
+        });
+
+        let host: Host = toml::from_str(&format!(
+            r#"hostname = ["127.0.0.1", "localhost"]
+port = {}
+username = "jozias"
+"#,
+            port
+        ))?;
+        let message = match connect(&host) {
+            Ok(_) => String::new(),
+            Err(e) => e.to_string(),
+        };
+        let _ = handle.join();
+        assert!(message.contains("127.0.0.1: "), "{}", message);
+        assert!(message.contains("localhost: "), "{}", message);
+        Ok(())
+    }
+
+    #[test]
+    fn split_lines() {
+        let mut lines = Lines::new(OutputStream::Stderr);
+        let mut seen = Vec::new();
+        lines.push(b"one\r\ntw", |line| seen.push(line));
+        assert_eq!(seen, vec!["one"]);
+        lines.push(b"o\n\nthr", |line| seen.push(line));
+        assert_eq!(seen, vec!["one", "two", ""]);
+        assert!(!lines.done);
+        lines.finish(|line| seen.push(line));
+        assert_eq!(seen, vec!["one", "two", "", "thr"]);
+        assert!(lines.done);
+    }
+
+    /// A remote `cat` with a small channel window: it only takes more input
+    /// once the output it echoed has been read.
+    struct WindowedCat {
+        window: usize,
+        echoed: Vec<u8>,
+        eof: bool,
+    }
+
+    impl Streams for WindowedCat {
+        fn read_stream(&mut self, stream_id: i32, buf: &mut [u8]) -> io::Result<usize> {
+            if stream_id == 0 && !self.echoed.is_empty() {
+                let read = buf.len().min(self.echoed.len());
+                buf[..read].copy_from_slice(&self.echoed[..read]);
+                let _ = self.echoed.drain(..read);
+                Ok(read)
+            } else if self.eof && self.echoed.is_empty() {
+                Ok(0)
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+
+        fn write_input(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let written = buf.len().min(self.window - self.echoed.len());
+            if written == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.echoed.extend_from_slice(&buf[..written]);
+            Ok(written)
+        }
+
+        fn close_input(&mut self) -> io::Result<()> {
+            self.eof = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pump_input_larger_than_the_window() -> MusshResult<()> {
+        let expected: Vec<String> = (0..10_000).map(|n| format!("line {}", n)).collect();
+        let payload = format!("{}\n", expected.join("\n"));
+        let mut channel = WindowedCat {
+            window: 1024,
+            echoed: Vec::new(),
+            eof: false,
+        };
+        let (tx, rx) = mpsc::channel();
+        let mut worker = Multiplex::default().worker("m1", Host::default());
+        worker.events = Some(tx);
+
+        let mut input = Input::new(Some(Box::new(Cursor::new(payload.into_bytes()))));
+        let mut stdout = Lines::new(OutputStream::Stdout);
+        let mut stderr = Lines::new(OutputStream::Stderr);
+        worker.pump(&mut channel, "cat", &mut input, &mut stdout, &mut stderr)?;
+        drop(worker);
+
+        assert!(input.closed);
+        let lines: Vec<String> = rx
+            .iter()
+            .filter_map(|event| match event {
+                Event::OutputLine { line, .. } => Some(line),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(lines, expected);
+        Ok(())
+    }
 }
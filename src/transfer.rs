@@ -0,0 +1,798 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! File transfers
+use crate::config::{Command, Fetch, Upload};
+use crate::error::{MusshErrKind, MusshResult};
+use crate::utils::{expand_path, fnv1a, shell_quote};
+use chrono::{TimeZone, Utc};
+use ssh2::{FileStat, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::OsStr;
+use std::fs::{self, DirBuilder, File, Metadata, OpenOptions, Permissions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::time::UNIX_EPOCH;
+
+mod sync;
+
+crate use self::sync::{sync_local, sync_remote, Synced};
+
+/// The mode a file is created with on the remote host when none is
+/// configured.
+const DEFAULT_MODE: u32 = 0o644;
+
+/// The directory files are fetched into when none is configured.
+const DEFAULT_FETCH_DIR: &str = "out";
+
+/// The files fetched from a host.
+#[derive(Debug, Default)]
+crate struct Fetched {
+    /// The number of bytes fetched
+    crate bytes: u64,
+    /// The files skipped for being larger than the maximum size
+    crate skipped: Vec<PathBuf>,
+}
+
+/// A file or directory on either side of a transfer.
+struct Entry {
+    path: PathBuf,
+    is_dir: bool,
+    size: u64,
+    /// The permission bits
+    mode: u32,
+    /// The modification time, in seconds since the epoch
+    mtime: u64,
+}
+
+impl Entry {
+    fn from_metadata(path: PathBuf, metadata: &Metadata) -> Self {
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+        Self {
+            path,
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            mode: metadata.permissions().mode() & 0o7777,
+            mtime,
+        }
+    }
+
+    fn from_stat(path: PathBuf, stat: &FileStat) -> Self {
+        Self {
+            path,
+            is_dir: stat.is_dir(),
+            size: stat.size.unwrap_or(0),
+            mode: stat.perm.unwrap_or(0) & 0o7777,
+            mtime: stat.mtime.unwrap_or(0),
+        }
+    }
+}
+
+/// The filesystem operations fetches and syncs need, on this host or over
+/// SFTP.
+trait Filesystem {
+    fn list(&self, dir: &Path) -> MusshResult<Vec<Entry>>;
+    fn stat(&self, path: &Path) -> MusshResult<Entry>;
+    fn open(&self, path: &Path) -> MusshResult<Box<dyn Read + '_>>;
+    fn create_dir(&self, path: &Path, mode: u32) -> MusshResult<()>;
+    /// Write a file, giving it the mode.
+    fn write(&self, path: &Path, source: &mut dyn Read, mode: u32) -> MusshResult<u64>;
+    /// Give the files their modification times, in seconds since the epoch.
+    fn set_mtimes(&self, files: &[(PathBuf, u64)]) -> MusshResult<()>;
+    fn remove(&self, entry: &Entry) -> MusshResult<()>;
+}
+
+/// The filesystem of this host.
+struct LocalFs;
+
+impl Filesystem for LocalFs {
+    fn list(&self, dir: &Path) -> MusshResult<Vec<Entry>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            entries.push(Entry::from_metadata(entry.path(), &entry.metadata()?));
+        }
+        Ok(entries)
+    }
+
+    fn stat(&self, path: &Path) -> MusshResult<Entry> {
+        Ok(Entry::from_metadata(
+            path.to_path_buf(),
+            &fs::metadata(path)?,
+        ))
+    }
+
+    fn open(&self, path: &Path) -> MusshResult<Box<dyn Read + '_>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create_dir(&self, path: &Path, mode: u32) -> MusshResult<()> {
+        fs::create_dir(path)?;
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, source: &mut dyn Read, mode: u32) -> MusshResult<u64> {
+        let mut dest = File::create(path)?;
+        let bytes = io::copy(source, &mut dest)?;
+        dest.set_permissions(Permissions::from_mode(mode))?;
+        Ok(bytes)
+    }
+
+    fn set_mtimes(&self, files: &[(PathBuf, u64)]) -> MusshResult<()> {
+        let mut by_mtime: BTreeMap<u64, Vec<&PathBuf>> = BTreeMap::new();
+        for (path, mtime) in files {
+            by_mtime.entry(*mtime).or_default().push(path);
+        }
+
+        // std cannot set modification times, so leave it to touch(1), once
+        // for every file with the same time, in UTC so the time is never
+        // ambiguous.
+        for (mtime, paths) in by_mtime {
+            let mtime = i64::try_from(mtime).unwrap_or(0);
+            let stamp = Utc
+                .timestamp_opt(mtime, 0)
+                .single()
+                .map_or_else(String::new, |time| time.format("%Y%m%d%H%M.%S").to_string());
+            let status = process::Command::new("touch")
+                .env("TZ", "UTC")
+                .arg("-m")
+                .arg("-t")
+                .arg(stamp)
+                .arg("--")
+                .args(paths)
+                .status()?;
+            if !status.success() {
+                let err_msg = "Failed to set modification times".to_string();
+                return Err(MusshErrKind::NonZero(err_msg, status.code()).into());
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&self, entry: &Entry) -> MusshResult<()> {
+        if entry.is_dir {
+            fs::remove_dir(&entry.path)?;
+        } else {
+            fs::remove_file(&entry.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Filesystem for Sftp {
+    fn list(&self, dir: &Path) -> MusshResult<Vec<Entry>> {
+        Ok(self
+            .readdir(dir)?
+            .into_iter()
+            .map(|(path, stat)| Entry::from_stat(path, &stat))
+            .collect())
+    }
+
+    fn stat(&self, path: &Path) -> MusshResult<Entry> {
+        Ok(Entry::from_stat(
+            path.to_path_buf(),
+            &Sftp::stat(self, path)?,
+        ))
+    }
+
+    fn open(&self, path: &Path) -> MusshResult<Box<dyn Read + '_>> {
+        Ok(Box::new(Sftp::open(self, path)?))
+    }
+
+    fn create_dir(&self, path: &Path, mode: u32) -> MusshResult<()> {
+        self.mkdir(path, create_mode(mode)?)?;
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, source: &mut dyn Read, mode: u32) -> MusshResult<u64> {
+        let mut dest = self.open_mode(
+            path,
+            OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+            create_mode(mode)?,
+            OpenType::File,
+        )?;
+        let bytes = io::copy(source, &mut dest)?;
+        drop(dest);
+
+        // The mode given at creation is subject to the remote umask.
+        let stat = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(mode),
+            atime: None,
+            mtime: None,
+        };
+        self.setstat(path, stat)?;
+        Ok(bytes)
+    }
+
+    fn set_mtimes(&self, files: &[(PathBuf, u64)]) -> MusshResult<()> {
+        for (path, mtime) in files {
+            let stat = FileStat {
+                size: None,
+                uid: None,
+                gid: None,
+                perm: None,
+                atime: Some(*mtime),
+                mtime: Some(*mtime),
+            };
+            self.setstat(path, stat)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&self, entry: &Entry) -> MusshResult<()> {
+        if entry.is_dir {
+            self.rmdir(&entry.path)?;
+        } else {
+            self.unlink(&entry.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copy the upload source to its destination on this host, returning the
+/// number of bytes copied.
+crate fn upload_local(upload: &Upload) -> MusshResult<u64> {
+    let mode = mode(upload)?;
+    let staging = staging_path(upload);
+    let result = write_local(upload, &staging, mode);
+
+    if is_atomic(upload) {
+        if result.is_ok() {
+            fs::rename(&staging, upload.dest())?;
+        } else {
+            let _ = fs::remove_file(&staging);
+        }
+    }
+    result
+}
+
+/// Copy the upload source to its destination over SFTP, returning the
+/// number of bytes copied.
+crate fn upload_remote(sess: &Session, upload: &Upload) -> MusshResult<u64> {
+    let mode = mode(upload)?;
+    let sftp = sess.sftp()?;
+    let staging = staging_path(upload);
+    let result = write_remote(sess, &sftp, upload, &staging, mode);
+
+    if is_atomic(upload) {
+        if result.is_ok() {
+            let dest = Path::new(upload.dest());
+            let flags = RenameFlags::ATOMIC | RenameFlags::OVERWRITE | RenameFlags::NATIVE;
+            // SFTP version 3 servers refuse to rename over an existing file,
+            // so fall back to rename(2) through the shell.
+            if sftp.rename(&staging, dest, Some(flags)).is_err() {
+                let mv = format!(
+                    "mv -f {} {}",
+                    shell_quote(&staging.to_string_lossy()),
+                    shell_quote(upload.dest())
+                );
+                exec_remote(sess, &mv)?;
+            }
+        } else {
+            let _ = sftp.unlink(&staging);
+        }
+    }
+    result
+}
+
+/// Fetch the files matching the source pattern on this host into
+/// `<dest>/<host>/<path>`.
+crate fn fetch_local(host: &str, fetch: &Fetch) -> MusshResult<Fetched> {
+    fetch_from(&LocalFs, host, fetch)
+}
+
+/// Fetch the files matching the source pattern over SFTP into
+/// `<dest>/<host>/<path>`.
+crate fn fetch_remote(sess: &Session, host: &str, fetch: &Fetch) -> MusshResult<Fetched> {
+    fetch_from(&sess.sftp()?, host, fetch)
+}
+
+fn fetch_from<F: Filesystem>(filesystem: &F, host: &str, fetch: &Fetch) -> MusshResult<Fetched> {
+    let files = expand(filesystem, fetch.source());
+    if files.is_empty() {
+        return Err(MusshErrKind::NoMatch(fetch.source().clone()).into());
+    }
+
+    let dest = fetch
+        .dest()
+        .as_ref()
+        .map_or(DEFAULT_FETCH_DIR, String::as_str);
+    let root = expand_path(dest).join(host);
+    let mut fetched = Fetched::default();
+
+    for file in files {
+        if fetch.max_size().map_or(false, |max| file.size > max) {
+            fetched.skipped.push(file.path);
+            continue;
+        }
+
+        let local = root.join(relative(&file.path));
+        if let Some(parent) = local.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut source = filesystem.open(&file.path)?;
+        let mut dest = File::create(&local)?;
+        fetched.bytes += io::copy(&mut source, &mut dest)?;
+    }
+    Ok(fetched)
+}
+
+/// Expand a pattern into the files it matches, in order.  Directories that
+/// cannot be read match nothing, and directories themselves are not fetched.
+fn expand<F: Filesystem>(filesystem: &F, pattern: &str) -> Vec<Entry> {
+    let mut candidates = vec![PathBuf::new()];
+
+    for component in Path::new(pattern).components() {
+        match component {
+            Component::Normal(name) if is_pattern(name) => {
+                let name = name.to_string_lossy();
+                let mut matched = Vec::new();
+                for dir in &candidates {
+                    let listing = if dir.as_os_str().is_empty() {
+                        Path::new(".")
+                    } else {
+                        dir.as_path()
+                    };
+                    if let Ok(entries) = filesystem.list(listing) {
+                        for entry in entries {
+                            if let Some(file_name) = entry.path.file_name() {
+                                if glob_match(&name, &file_name.to_string_lossy()) {
+                                    matched.push(dir.join(file_name));
+                                }
+                            }
+                        }
+                    }
+                }
+                matched.sort();
+                candidates = matched;
+            }
+            component => {
+                for candidate in &mut candidates {
+                    candidate.push(component);
+                }
+            }
+        }
+    }
+
+    candidates
+        .iter()
+        .filter_map(|path| filesystem.stat(path).ok())
+        .filter(|entry| !entry.is_dir)
+        .collect()
+}
+
+/// The path of a fetched file below the host directory.  Only the normal
+/// components are kept, so a file can never land outside of it.
+fn relative(path: &Path) -> PathBuf {
+    path.components()
+        .filter_map(|component| match component {
+            Component::Normal(name) => Some(name),
+            _ => None,
+        })
+        .collect()
+}
+
+fn is_pattern(name: &OsStr) -> bool {
+    name.to_string_lossy().contains(&['*', '?', '['][..])
+}
+
+/// Does the name match the shell wildcard pattern?  As in the shell, a
+/// leading `.` must be matched explicitly.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    if name.starts_with('.') && !pattern.starts_with('.') {
+        return false;
+    }
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches(&pattern, &name)
+}
+
+fn matches(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some(('*', rest)), _) => (0..=name.len()).any(|skip| matches(rest, &name[skip..])),
+        (Some(('?', rest)), Some((_, name_rest))) => matches(rest, name_rest),
+        (Some(('[', rest)), Some((ch, name_rest))) => match class(rest, *ch) {
+            Some((matched, after)) => matched && matches(after, name_rest),
+            // An unclosed class is a literal '['
+            None => *ch == '[' && matches(rest, name_rest),
+        },
+        (Some((expected, rest)), Some((ch, name_rest))) => {
+            expected == ch && matches(rest, name_rest)
+        }
+        (Some(_), None) => false,
+    }
+}
+
+/// Match a character against a `[...]` class, given the pattern after the
+/// `[`.  Returns whether it matched and the pattern after the `]`, or `None`
+/// if the class is never closed.
+fn class(pattern: &[char], ch: char) -> Option<(bool, &[char])> {
+    let negated = pattern
+        .first()
+        .map_or(false, |first| *first == '!' || *first == '^');
+    let mut idx = usize::from(negated);
+    let start = idx;
+    let mut matched = false;
+
+    while let Some(first) = pattern.get(idx) {
+        // A ']' first in the class is a literal
+        if *first == ']' && idx > start {
+            return Some((matched != negated, &pattern[idx + 1..]));
+        }
+        match (pattern.get(idx + 1), pattern.get(idx + 2)) {
+            (Some('-'), Some(last)) if *last != ']' => {
+                matched |= *first <= ch && ch <= *last;
+                idx += 3;
+            }
+            _ => {
+                matched |= *first == ch;
+                idx += 1;
+            }
+        }
+    }
+    None
+}
+
+fn write_local(upload: &Upload, staging: &Path, mode: Option<u32>) -> MusshResult<u64> {
+    let mut source = File::open(expand_path(upload.source()))?;
+    // A staging file is never written over, in case another is already there
+    let mut dest = if is_atomic(upload) {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(staging)?
+    } else {
+        File::create(staging)?
+    };
+    let bytes = io::copy(&mut source, &mut dest)?;
+
+    if let Some(mode) = mode {
+        dest.set_permissions(Permissions::from_mode(mode))?;
+    }
+    if let Some(owner) = upload.owner() {
+        let status = process::Command::new("chown")
+            .arg(owner)
+            .arg(staging)
+            .status()?;
+        if !status.success() {
+            let err_msg = format!("Failed to chown '{}' to '{}'", upload.dest(), owner);
+            return Err(MusshErrKind::NonZero(err_msg, status.code()).into());
+        }
+    }
+    Ok(bytes)
+}
+
+fn write_remote(
+    sess: &Session,
+    sftp: &Sftp,
+    upload: &Upload,
+    staging: &Path,
+    mode: Option<u32>,
+) -> MusshResult<u64> {
+    let mut source = File::open(expand_path(upload.source()))?;
+    let flags = if is_atomic(upload) {
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::EXCLUSIVE
+    } else {
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE
+    };
+    let mut dest = sftp.open_mode(
+        staging,
+        flags,
+        create_mode(mode.unwrap_or(DEFAULT_MODE))?,
+        OpenType::File,
+    )?;
+    let bytes = io::copy(&mut source, &mut dest)?;
+    drop(dest);
+
+    // The mode given at creation is subject to the remote umask.
+    if let Some(mode) = mode {
+        let stat = FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(mode),
+            atime: None,
+            mtime: None,
+        };
+        sftp.setstat(staging, stat)?;
+    }
+    if let Some(owner) = upload.owner() {
+        let chown = format!(
+            "chown {} {}",
+            shell_quote(owner),
+            shell_quote(&staging.to_string_lossy())
+        );
+        exec_remote(sess, &chown)?;
+    }
+    Ok(bytes)
+}
+
+/// Create a directory only the user can use, to stage scripts in on this
+/// host.  It is created afresh, so nothing else can have put files in it.
+crate fn script_dir_local() -> MusshResult<String> {
+    let random = RandomState::new().build_hasher().finish();
+    let dir = env::temp_dir().join(format!("mussh-run-{}-{:016x}", process::id(), random));
+    DirBuilder::new().mode(0o700).create(&dir)?;
+    Ok(dir.to_string_lossy().to_string())
+}
+
+/// Create a directory only the user can use, to stage scripts in on the
+/// remote host, with `mktemp -d`.
+crate fn script_dir_remote(sess: &Session) -> MusshResult<String> {
+    let output = output_remote(sess, "mktemp -d")?;
+    let dir = String::from_utf8_lossy(&output).trim_end().to_string();
+    if dir.is_empty() {
+        Err("mktemp -d did not name a directory".into())
+    } else {
+        Ok(dir)
+    }
+}
+
+/// The path a script is staged at in the given directory.  It starts with
+/// the script's checksum, so a script changed during the run is staged
+/// afresh, and ends with the script's file name, which scripts may report as
+/// `$0`.
+crate fn script_path(dir: &str, script: &str, contents: &[u8]) -> String {
+    let name = Path::new(script)
+        .file_name()
+        .map_or_else(|| "script".into(), OsStr::to_string_lossy);
+    format!("{}/{:016x}-{}", dir, fnv1a(contents), name)
+}
+
+/// The command line that runs the script staged at the given path.
+crate fn script_command(cmd: &Command, path: &str) -> String {
+    let mut line = match cmd.interpreter() {
+        Some(interpreter) => format!("{} {}", interpreter, shell_quote(path)),
+        None => shell_quote(path),
+    };
+    for arg in cmd.args().iter().flatten() {
+        line.push(' ');
+        line.push_str(&shell_quote(arg));
+    }
+    line
+}
+
+/// Run a helper command on the remote host, failing if it exits non-zero.
+crate fn exec_remote(sess: &Session, cmd: &str) -> MusshResult<()> {
+    let _ = output_remote(sess, cmd)?;
+    Ok(())
+}
+
+/// Run a helper command on the remote host, returning its stdout, failing if
+/// it exits non-zero.
+crate fn output_remote(sess: &Session, cmd: &str) -> MusshResult<Vec<u8>> {
+    let mut channel = sess.channel_session()?;
+    channel.exec(cmd)?;
+    let mut output = Vec::new();
+    let _ = channel.read_to_end(&mut output)?;
+    channel.wait_close()?;
+
+    match channel.exit_status()? {
+        0 => Ok(output),
+        code => Err(MusshErrKind::NonZero(format!("Failed to run '{}'", cmd), Some(code)).into()),
+    }
+}
+
+fn is_atomic(upload: &Upload) -> bool {
+    upload.atomic().unwrap_or(false)
+}
+
+/// The path the file is written to.  Atomic uploads are written beside the
+/// destination, under a name unique to this upload, and renamed into place.
+fn staging_path(upload: &Upload) -> PathBuf {
+    if is_atomic(upload) {
+        let random = RandomState::new().build_hasher().finish();
+        PathBuf::from(format!(
+            "{}.mussh-tmp-{}-{:016x}",
+            upload.dest(),
+            process::id(),
+            random
+        ))
+    } else {
+        PathBuf::from(upload.dest())
+    }
+}
+
+/// Permission bits as the mode SFTP creates files with.
+fn create_mode(mode: u32) -> MusshResult<i32> {
+    i32::try_from(mode).map_err(|_| MusshErrKind::InvalidMode(format!("{:o}", mode)).into())
+}
+
+/// The configured mode as permission bits.
+fn mode(upload: &Upload) -> MusshResult<Option<u32>> {
+    match upload.mode() {
+        Some(mode) => match u32::from_str_radix(mode, 8) {
+            Ok(bits) if bits <= 0o7777 => Ok(Some(bits)),
+            _ => Err(MusshErrKind::InvalidMode(mode.clone()).into()),
+        },
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LocalFs;
+    use super::{
+        expand, fetch_local, glob_match, mode, relative, script_command, script_path, staging_path,
+        upload_local,
+    };
+    use crate::config::{Command, Fetch, Upload};
+    use crate::error::MusshResult;
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    fn scratch(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("mussh-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn modes() {
+        let mut upload = Upload::default();
+        assert_eq!(mode(&upload).ok(), Some(None));
+        let _ = upload.set_mode(Some("0640".to_string()));
+        assert_eq!(mode(&upload).ok(), Some(Some(0o640)));
+        let _ = upload.set_mode(Some("755".to_string()));
+        assert_eq!(mode(&upload).ok(), Some(Some(0o755)));
+        let _ = upload.set_mode(Some("0x644".to_string()));
+        assert!(mode(&upload).is_err());
+        let _ = upload.set_mode(Some("17777".to_string()));
+        assert!(mode(&upload).is_err());
+    }
+
+    #[test]
+    fn staging() {
+        let mut upload = Upload::default();
+        let _ = upload.set_dest("/etc/motd".to_string());
+        assert_eq!(staging_path(&upload), PathBuf::from("/etc/motd"));
+        let _ = upload.set_atomic(Some(true));
+        let staging = staging_path(&upload).to_string_lossy().to_string();
+        let prefix = format!("/etc/motd.mussh-tmp-{}-", std::process::id());
+        assert!(staging.starts_with(&prefix));
+        assert_ne!(staging_path(&upload), staging_path(&upload));
+    }
+
+    /// The staging files left beside the destination.
+    fn staged(dest: &Path) -> MusshResult<Vec<PathBuf>> {
+        let prefix = format!("{}.mussh-tmp", dest.to_string_lossy());
+        Ok(fs::read_dir(env::temp_dir())?
+            .filter_map(Result::ok)
+            .map(|entry| entry.path())
+            .filter(|path| path.to_string_lossy().starts_with(&prefix))
+            .collect())
+    }
+
+    #[test]
+    fn upload_to_localhost() -> MusshResult<()> {
+        let source = scratch("upload-source");
+        let dest = scratch("upload-dest");
+        fs::write(&source, "welcome\n")?;
+        fs::write(&dest, "stale\n")?;
+
+        let mut upload = Upload::default();
+        let _ = upload.set_source(source.to_string_lossy().to_string());
+        let _ = upload.set_dest(dest.to_string_lossy().to_string());
+        let _ = upload.set_mode(Some("0600".to_string()));
+        let _ = upload.set_atomic(Some(true));
+
+        assert_eq!(upload_local(&upload)?, 8);
+        assert_eq!(fs::read_to_string(&dest)?, "welcome\n");
+        assert_eq!(fs::metadata(&dest)?.permissions().mode() & 0o7777, 0o600);
+        assert!(staged(&dest)?.is_empty());
+
+        let _ = upload.set_source(scratch("upload-missing").to_string_lossy().to_string());
+        assert!(upload_local(&upload).is_err());
+        assert_eq!(fs::read_to_string(&dest)?, "welcome\n");
+        assert!(staged(&dest)?.is_empty());
+
+        fs::remove_file(&source)?;
+        fs::remove_file(&dest)?;
+        Ok(())
+    }
+
+    #[test]
+    fn script_commands() {
+        let path = script_path("/tmp/d", "~/bin/deploy.sh", b"echo hi");
+        assert!(path.starts_with("/tmp/d/"));
+        assert!(path.ends_with("-deploy.sh"));
+        assert_eq!(path, script_path("/tmp/d", "~/bin/deploy.sh", b"echo hi"));
+        assert_ne!(path, script_path("/tmp/d", "~/bin/deploy.sh", b"echo bye"));
+        assert!(script_path("/tmp/d", "/", b"echo hi").ends_with("-script"));
+
+        let mut cmd = Command::default();
+        assert_eq!(script_command(&cmd, "/tmp/s"), "'/tmp/s'");
+        let _ = cmd
+            .set_interpreter(Some("python3 -u".to_string()))
+            .set_args(Some(vec!["--days".to_string(), "7 days".to_string()]));
+        assert_eq!(
+            script_command(&cmd, "/tmp/s"),
+            "python3 -u '/tmp/s' '--days' '7 days'"
+        );
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("*.log", "syslog.log"));
+        assert!(!glob_match("*.log", "syslog.log.1"));
+        assert!(!glob_match("*", ".bashrc"));
+        assert!(glob_match(".*", ".bashrc"));
+        assert!(glob_match("app-?.conf", "app-1.conf"));
+        assert!(!glob_match("app-?.conf", "app-10.conf"));
+        assert!(glob_match("m[0-9][!a]", "m1b"));
+        assert!(!glob_match("m[0-9][!a]", "m1a"));
+        assert!(glob_match("[]x]", "]"));
+        assert!(glob_match("a[b", "a[b"));
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(
+            relative(Path::new("/var/log/syslog")),
+            PathBuf::from("var/log/syslog")
+        );
+        assert_eq!(
+            relative(Path::new("./../etc/hosts")),
+            PathBuf::from("etc/hosts")
+        );
+    }
+
+    #[test]
+    fn fetch_from_localhost() -> MusshResult<()> {
+        let root = env::temp_dir().join(format!("mussh-fetch-{}", std::process::id()));
+        let logs = root.join("logs");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(logs.join("app"))?;
+        fs::write(logs.join("a.log"), "a\n")?;
+        fs::write(logs.join("b.log"), "bbbbbbbbbb\n")?;
+        fs::write(logs.join("c.txt"), "c\n")?;
+        fs::write(logs.join("app").join("d.log"), "d\n")?;
+
+        let pattern = format!("{}/*/*.log", root.display());
+        let paths: Vec<PathBuf> = expand(&LocalFs, &pattern)
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        assert_eq!(paths, vec![logs.join("a.log"), logs.join("b.log")]);
+
+        let out = root.join("out");
+        let mut fetch = Fetch::default();
+        let _ = fetch.set_source(format!("{}/logs/*.log", root.display()));
+        let _ = fetch.set_dest(Some(out.to_string_lossy().to_string()));
+        let _ = fetch.set_max_size(Some(4));
+
+        let fetched = fetch_local("m1", &fetch)?;
+        assert_eq!(fetched.bytes, 2);
+        assert_eq!(fetched.skipped, vec![logs.join("b.log")]);
+        let local = out.join("m1").join(relative(&logs)).join("a.log");
+        assert_eq!(fs::read_to_string(local)?, "a\n");
+
+        let _ = fetch.set_source(format!("{}/logs/*.gz", root.display()));
+        assert!(fetch_local("m1", &fetch).is_err());
+
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright © 2018 libmussh developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Directory synchronization
+use super::{matches, output_remote, Entry, Filesystem, LocalFs};
+use crate::config::DirSync;
+use crate::error::{MusshErrKind, MusshResult};
+use crate::utils::{expand_path, shell_quote};
+use ssh2::Session;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A change a sync made, or would make in check mode, relative to the
+/// directory.
+#[derive(Clone, Debug, Eq, PartialEq)]
+crate enum Change {
+    Created(PathBuf),
+    Updated(PathBuf),
+    Deleted(PathBuf),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Created(path) => write!(f, "created {}", path.display()),
+            Change::Updated(path) => write!(f, "updated {}", path.display()),
+            Change::Deleted(path) => write!(f, "deleted {}", path.display()),
+        }
+    }
+}
+
+/// The changes a sync made to a host.
+#[derive(Debug, Default)]
+crate struct Synced {
+    /// The number of bytes written
+    crate bytes: u64,
+    /// The changes, in the order they were made
+    crate changes: Vec<Change>,
+}
+
+/// Synchronize the source directory to the destination on this host.
+crate fn sync_local(sync: &DirSync) -> MusshResult<Synced> {
+    sync_to(&LocalFs, sha256sum_local, sync)
+}
+
+/// Synchronize the source directory to the destination over SFTP.
+crate fn sync_remote(sess: &Session, sync: &DirSync) -> MusshResult<Synced> {
+    sync_to(&sess.sftp()?, |paths| sha256sum_remote(sess, paths), sync)
+}
+
+/// Synchronize the source directory to the destination on the target, which
+/// `sha256sum` runs the checksums of its files.
+fn sync_to<F, H>(target: &F, sha256sum: H, sync: &DirSync) -> MusshResult<Synced>
+where
+    F: Filesystem,
+    H: Fn(&[PathBuf]) -> MusshResult<Vec<u8>>,
+{
+    let exclude = sync.exclude().as_ref().map_or(&[][..], Vec::as_slice);
+    let check = sync.check().unwrap_or(false);
+    let checksum = sync.checksum().unwrap_or(false);
+    let source_root = expand_path(sync.source());
+    let dest_root = PathBuf::from(sync.dest());
+
+    let source = walk(&LocalFs, &source_root, exclude)?;
+    let dest = match target.stat(&dest_root) {
+        Ok(ref root) if root.is_dir => walk(target, &dest_root, exclude)?,
+        Ok(_) => return Err(MusshErrKind::SyncConflict(sync.dest().clone()).into()),
+        Err(_) => {
+            if !check {
+                target.create_dir(&dest_root, LocalFs.stat(&source_root)?.mode)?;
+            }
+            BTreeMap::new()
+        }
+    };
+
+    // Only the files that look unchanged need their checksums compared
+    let unchanged: Vec<&PathBuf> = source
+        .iter()
+        .filter(|(relative, entry)| match dest.get(*relative) {
+            Some(existing) => !entry.is_dir && !existing.is_dir && !differs(entry, existing),
+            None => false,
+        })
+        .map(|(relative, _)| relative)
+        .collect();
+    let (source_sums, dest_sums) = if checksum && !unchanged.is_empty() {
+        (
+            checksums(&source_root, &unchanged, sha256sum_local)?,
+            checksums(&dest_root, &unchanged, sha256sum)?,
+        )
+    } else {
+        (BTreeMap::new(), BTreeMap::new())
+    };
+
+    let mut synced = Synced::default();
+    let mut written = Vec::new();
+    for (relative, entry) in &source {
+        let path = dest_root.join(relative);
+        let change = match dest.get(relative) {
+            None => Change::Created(relative.clone()),
+            Some(existing) if existing.is_dir != entry.is_dir => {
+                return Err(MusshErrKind::SyncConflict(path.display().to_string()).into());
+            }
+            Some(existing) => {
+                let updated = differs(entry, existing)
+                    || source_sums.get(relative) != dest_sums.get(relative);
+                if entry.is_dir || !updated {
+                    continue;
+                }
+                Change::Updated(relative.clone())
+            }
+        };
+
+        if !check {
+            if entry.is_dir {
+                target.create_dir(&path, entry.mode)?;
+            } else {
+                let mut file = LocalFs.open(&entry.path)?;
+                synced.bytes += target.write(&path, &mut file, entry.mode)?;
+                written.push((path, entry.mtime));
+            }
+        }
+        synced.changes.push(change);
+    }
+    target.set_mtimes(&written)?;
+
+    if sync.delete().unwrap_or(false) {
+        // In reverse, so a directory's contents are deleted before it is
+        for (relative, entry) in dest.iter().rev() {
+            if !source.contains_key(relative) {
+                if !check {
+                    target.remove(entry)?;
+                }
+                synced.changes.push(Change::Deleted(relative.clone()));
+            }
+        }
+    }
+    Ok(synced)
+}
+
+/// Every file and directory below the root that is not excluded, by path
+/// relative to the root.
+fn walk<F: Filesystem>(
+    filesystem: &F,
+    root: &Path,
+    exclude: &[String],
+) -> MusshResult<BTreeMap<PathBuf, Entry>> {
+    let mut tree = BTreeMap::new();
+    let mut dirs = vec![root.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in filesystem.list(&dir)? {
+            let relative = match entry.path.strip_prefix(root) {
+                Ok(relative) => relative.to_path_buf(),
+                Err(_) => continue,
+            };
+            if is_excluded(&relative, exclude) {
+                continue;
+            }
+            if entry.is_dir {
+                dirs.push(entry.path.clone());
+            }
+            let _ = tree.insert(relative, entry);
+        }
+    }
+    Ok(tree)
+}
+
+/// Does the destination file differ from the source file by size or
+/// modification time?
+fn differs(source: &Entry, dest: &Entry) -> bool {
+    source.size != dest.size || source.mtime != dest.mtime
+}
+
+/// The SHA-256 checksums of the files below the root, by path relative to
+/// the root.  `sha256sum` is run once per directory.
+fn checksums<H>(
+    root: &Path,
+    files: &[&PathBuf],
+    sha256sum: H,
+) -> MusshResult<BTreeMap<PathBuf, String>>
+where
+    H: Fn(&[PathBuf]) -> MusshResult<Vec<u8>>,
+{
+    let mut dirs: BTreeMap<Option<&Path>, Vec<&PathBuf>> = BTreeMap::new();
+    for relative in files {
+        dirs.entry(relative.parent()).or_default().push(relative);
+    }
+
+    let mut sums = BTreeMap::new();
+    for relatives in dirs.values() {
+        let paths: Vec<PathBuf> = relatives
+            .iter()
+            .map(|relative| root.join(relative))
+            .collect();
+        let output = sha256sum(&paths)?;
+        // A line per file, in order.  Names needing escapes start with `\`.
+        let digests: Vec<String> = String::from_utf8_lossy(&output)
+            .lines()
+            .filter_map(|line| line.trim_start_matches('\\').split_whitespace().next())
+            .map(str::to_string)
+            .collect();
+        if digests.len() != relatives.len() {
+            return Err("sha256sum did not list every file".into());
+        }
+        sums.extend(
+            relatives
+                .iter()
+                .map(|relative| (*relative).clone())
+                .zip(digests),
+        );
+    }
+    Ok(sums)
+}
+
+/// Run `sha256sum` on this host, returning its output.
+fn sha256sum_local(paths: &[PathBuf]) -> MusshResult<Vec<u8>> {
+    let output = process::Command::new("sha256sum")
+        .arg("--")
+        .args(paths)
+        .output()?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        let err_msg = "Failed to run 'sha256sum'".to_string();
+        Err(MusshErrKind::NonZero(err_msg, output.status.code()).into())
+    }
+}
+
+/// Run `sha256sum` on the remote host, returning its output.
+fn sha256sum_remote(sess: &Session, paths: &[PathBuf]) -> MusshResult<Vec<u8>> {
+    let paths: Vec<String> = paths
+        .iter()
+        .map(|path| shell_quote(&path.to_string_lossy()))
+        .collect();
+    output_remote(sess, &format!("sha256sum -- {}", paths.join(" ")))
+}
+
+/// Is the path excluded?  Unlike the fetch patterns, a leading `.` is
+/// matched by wildcards, so `*.swp` excludes `.motd.swp`.
+fn is_excluded(relative: &Path, exclude: &[String]) -> bool {
+    let components: Vec<String> = relative
+        .iter()
+        .map(|component| component.to_string_lossy().to_string())
+        .collect();
+
+    exclude.iter().any(|pattern| {
+        if pattern.contains('/') {
+            let parts: Vec<&str> = pattern.trim_matches('/').split('/').collect();
+            parts.len() == components.len()
+                && parts
+                    .iter()
+                    .zip(&components)
+                    .all(|(part, component)| glob(part, component))
+        } else {
+            components.last().map_or(false, |name| glob(pattern, name))
+        }
+    })
+}
+
+fn glob(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    matches(&pattern, &name)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_excluded, sync_local, Change};
+    use crate::config::DirSync;
+    use crate::error::MusshResult;
+    use crate::transfer::{Filesystem, LocalFs};
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    fn changes(sync: &DirSync) -> MusshResult<Vec<String>> {
+        Ok(sync_local(sync)?
+            .changes
+            .iter()
+            .map(Change::to_string)
+            .collect())
+    }
+
+    #[test]
+    fn excludes() {
+        let exclude = vec!["*.swp".to_string(), "cache/*".to_string()];
+        assert!(is_excluded(Path::new("conf/.motd.swp"), &exclude));
+        assert!(is_excluded(Path::new("cache/index"), &exclude));
+        assert!(!is_excluded(Path::new("conf/cache/index"), &exclude));
+        assert!(!is_excluded(Path::new("cache"), &exclude));
+        assert!(!is_excluded(Path::new("motd"), &exclude));
+    }
+
+    #[test]
+    fn sync_to_localhost() -> MusshResult<()> {
+        let root = env::temp_dir().join(format!("mussh-sync-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let source = root.join("source");
+        let dest = root.join("dest");
+        fs::create_dir_all(source.join("conf.d"))?;
+        fs::write(source.join("conf.d").join("app.conf"), "port = 80\n")?;
+        fs::write(source.join("motd"), "hello\n")?;
+        fs::write(source.join("motd.swp"), "swap\n")?;
+
+        let mut sync = DirSync::default();
+        let _ = sync
+            .set_source(source.to_string_lossy().to_string())
+            .set_dest(dest.to_string_lossy().to_string())
+            .set_exclude(Some(vec!["*.swp".to_string()]))
+            .set_check(Some(true));
+        let created = vec!["created conf.d", "created conf.d/app.conf", "created motd"];
+        assert_eq!(changes(&sync)?, created);
+        assert!(!dest.exists());
+
+        let _ = sync.set_check(None);
+        assert_eq!(changes(&sync)?, created);
+        assert_eq!(fs::read_to_string(dest.join("motd"))?, "hello\n");
+        assert!(!dest.join("motd.swp").exists());
+        let motd = LocalFs.stat(&source.join("motd"))?;
+        assert_eq!(LocalFs.stat(&dest.join("motd"))?.mtime, motd.mtime);
+        assert!(changes(&sync)?.is_empty());
+
+        // Same size and modification time, so only a checksum notices
+        let mut stale: &[u8] = b"howdy\n";
+        let _ = LocalFs.write(&dest.join("motd"), &mut stale, motd.mode)?;
+        LocalFs.set_mtimes(&[(dest.join("motd"), motd.mtime)])?;
+        assert!(changes(&sync)?.is_empty());
+        let _ = sync.set_checksum(Some(true));
+        assert_eq!(changes(&sync)?, vec!["updated motd"]);
+        assert_eq!(fs::read_to_string(dest.join("motd"))?, "hello\n");
+
+        // The same contents, but a different modification time
+        LocalFs.set_mtimes(&[(dest.join("motd"), motd.mtime + 60)])?;
+        assert_eq!(changes(&sync)?, vec!["updated motd"]);
+        assert_eq!(LocalFs.stat(&dest.join("motd"))?.mtime, motd.mtime);
+
+        fs::write(dest.join("extra"), "extra\n")?;
+        fs::write(dest.join("keep.swp"), "swap\n")?;
+        fs::remove_dir_all(source.join("conf.d"))?;
+        let _ = sync.set_delete(Some(true)).set_check(Some(true));
+        let deleted = vec!["deleted extra", "deleted conf.d/app.conf", "deleted conf.d"];
+        assert_eq!(changes(&sync)?, deleted);
+        assert!(dest.join("extra").exists());
+        let _ = sync.set_check(None);
+        assert_eq!(changes(&sync)?, deleted);
+        let mut left: Vec<PathBuf> = fs::read_dir(&dest)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.file_name().into()))
+            .collect();
+        left.sort();
+        assert_eq!(left, vec![PathBuf::from("keep.swp"), PathBuf::from("motd")]);
+
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
@@ -7,12 +7,20 @@
 // modified, or distributed except according to those terms.
 
 //! Utilities
-use crate::config::Host;
+use crate::config::{Command, Host};
+use crate::error::{MusshErrKind, MusshResult};
 use clap::Values;
 use indexmap::{IndexMap, IndexSet};
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::env;
 use std::fmt;
 use std::hash::Hash;
 use std::iter::FromIterator;
+use std::net::{IpAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::process;
 use std::time::Duration;
 
 /// Type used by multiplex to run commands on hosts
@@ -20,7 +28,33 @@ use std::time::Duration;
 /// This is a map of the following: `Host Name` to `Command Tuple`
 /// The `Command Tuple` consists of a `Host` and a `CmdType` map
 /// The `CmdType` map contains a map of `Command Name` to actual `Command`
-pub type MultiplexMapType = IndexMap<String, (Host, IndexMap<CmdType, IndexMap<String, String>>)>;
+pub type MultiplexMapType = IndexMap<String, (Host, IndexMap<CmdType, IndexMap<String, Command>>)>;
+
+/// Type used by multiplex before 2.0, when commands were only command lines
+///
+/// This is the `MultiplexMapType`, with a `Command Line` in place of each
+/// `Command`.  Convert it with `from_command_lines`.
+pub type CommandLineMapType = IndexMap<String, (Host, IndexMap<CmdType, IndexMap<String, String>>)>;
+
+/// Convert a map of command lines into one multiplex can run.
+#[must_use]
+pub fn from_command_lines(map: CommandLineMapType) -> MultiplexMapType {
+    map.into_iter()
+        .map(|(hostname, (host, cmd_types))| {
+            let cmd_types = cmd_types
+                .into_iter()
+                .map(|(cmd_type, cmds)| {
+                    let cmds = cmds
+                        .into_iter()
+                        .map(|(cmd_name, line)| (cmd_name, Command::from(line)))
+                        .collect();
+                    (cmd_type, cmds)
+                })
+                .collect();
+            (hostname, (host, cmd_types))
+        })
+        .collect()
+}
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 #[allow(dead_code)]
@@ -29,9 +63,13 @@ crate enum HostType {
     SyncHost,
 }
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+/// The phase a command runs in
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum CmdType {
+    /// A command run as soon as the host is ready
     Cmd,
+    /// A command run on the sync hosts first, then on the other hosts
     SyncCmd,
 }
 
@@ -68,6 +106,295 @@ crate fn map_vals(values: Values<'_>) -> Vec<String> {
     values.map(ToString::to_string).collect()
 }
 
+/// Expand a leading `~` to the home directory, and `$VAR` or `${VAR}` to the
+/// value of the environment variable.  Unset variables are left as is.
+crate fn expand_path(path: &str) -> PathBuf {
+    let path = match (path.strip_prefix('~'), env::var("HOME")) {
+        (Some(rest), Ok(home)) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{}", home, rest)
+        }
+        _ => path.to_string(),
+    };
+
+    let mut expanded = String::new();
+    let mut chars = path.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            expanded.push(c);
+            continue;
+        }
+
+        let braced = chars.peek() == Some(&'{');
+        if braced {
+            let _ = chars.next();
+        }
+
+        let mut name = String::new();
+        while let Some(&n) = chars.peek() {
+            if n.is_ascii_alphanumeric() || n == '_' {
+                name.push(n);
+                let _ = chars.next();
+            } else {
+                break;
+            }
+        }
+
+        let closed = !braced || chars.peek() == Some(&'}');
+        if braced && closed {
+            let _ = chars.next();
+        }
+
+        match env::var(&name) {
+            Ok(value) if !name.is_empty() && closed => expanded.push_str(&value),
+            _ => {
+                expanded.push('$');
+                if braced {
+                    expanded.push('{');
+                }
+                expanded.push_str(&name);
+                if braced && closed {
+                    expanded.push('}');
+                }
+            }
+        }
+    }
+
+    PathBuf::from(expanded)
+}
+
+/// Encode bytes as unpadded base64, as used in OpenSSH key fingerprints.
+crate fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+
+        for i in 0..=chunk.len() {
+            encoded.push(char::from(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize]));
+        }
+    }
+    encoded
+}
+
+/// The 64-bit FNV-1a hash of the given bytes.
+crate fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01b3;
+
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+lazy_static! {
+    /// The names of this host, and its addresses: those of its interfaces,
+    /// and those its names resolve to.
+    static ref OWN_HOST: (Vec<String>, Vec<IpAddr>) = own_host();
+}
+
+/// Is the hostname `localhost`, a loopback address, or one of this host's
+/// own names or addresses?
+///
+/// The first call looks the names and addresses up by running `hostname`,
+/// and `ifconfig` where `hostname -I` is not supported, and resolving the
+/// names, so it can block until they finish.  Later calls reuse the result.
+crate fn is_own_host(hostname: &str) -> bool {
+    let (names, addresses) = &*OWN_HOST;
+    let hostname = hostname.trim_end_matches('.').to_lowercase();
+    match hostname.trim_matches(&['[', ']'][..]).parse::<IpAddr>() {
+        Ok(address) => address.is_loopback() || addresses.contains(&address),
+        Err(_) => hostname == "localhost" || names.contains(&hostname),
+    }
+}
+
+fn own_host() -> (Vec<String>, Vec<IpAddr>) {
+    // The short name, then the fully qualified one
+    let mut names: Vec<String> = [None, Some("-f")]
+        .iter()
+        .filter_map(|flag| process::Command::new("hostname").args(flag).output().ok())
+        .filter(|output| output.status.success())
+        .map(|output| {
+            String::from_utf8_lossy(&output.stdout)
+                .trim()
+                .to_lowercase()
+        })
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.dedup();
+
+    // A name often resolves to a loopback address only, such as Debian's
+    // 127.0.1.1, so the interfaces are asked for their addresses too.
+    let mut addresses = interface_addresses();
+    addresses.extend(
+        names
+            .iter()
+            .filter_map(|name| (name.as_str(), 0).to_socket_addrs().ok())
+            .flatten()
+            .map(|addr| addr.ip()),
+    );
+    addresses.sort();
+    addresses.dedup();
+    (names, addresses)
+}
+
+/// The addresses of this host's interfaces, from `hostname -I` where it is
+/// supported, and from `ifconfig -a` otherwise.
+fn interface_addresses() -> Vec<IpAddr> {
+    let output = |program: &str, arg: &str| {
+        process::Command::new(program)
+            .arg(arg)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).to_string())
+    };
+
+    match output("hostname", "-I") {
+        Some(listed) => listed
+            .split_whitespace()
+            .filter_map(|address| address.parse().ok())
+            .collect(),
+        None => output("ifconfig", "-a")
+            .map(|listed| ifconfig_addresses(&listed))
+            .unwrap_or_default(),
+    }
+}
+
+/// The addresses in `ifconfig` output: the word after each `inet` or
+/// `inet6`, without an `addr:` prefix, a `%` zone, or a `/` prefix length.
+fn ifconfig_addresses(listed: &str) -> Vec<IpAddr> {
+    let words: Vec<&str> = listed.split_whitespace().collect();
+    (1..words.len())
+        .filter(|idx| words[idx - 1] == "inet" || words[idx - 1] == "inet6")
+        .filter_map(|idx| {
+            // Older Linux tools write `inet6 addr: <address>`
+            let address = match words[idx].trim_start_matches("addr:") {
+                "" => words.get(idx + 1)?,
+                address => address,
+            };
+            address.split(&['%', '/'][..]).next()?.parse().ok()
+        })
+        .collect()
+}
+
+/// Quote the given string for use as a single word in a POSIX shell.
+crate fn shell_quote(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
+
+/// Quote the words for use as a command line in a POSIX shell, so the shell
+/// runs them as they are.
+crate fn quote_words<S: AsRef<str>>(words: &[S]) -> String {
+    words
+        .iter()
+        .map(|word| shell_quote(word.as_ref()))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Split a command line into words as a POSIX shell would, honouring
+/// quotes and backslashes, but without any expansion.
+crate fn split_words(line: &str) -> MusshResult<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(MusshErrKind::UnbalancedQuote(line.to_string()).into()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c) if "$`\"\\\n".contains(c) => {
+                                if c != '\n' {
+                                    word.push(c);
+                                }
+                            }
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => {
+                                return Err(MusshErrKind::UnbalancedQuote(line.to_string()).into())
+                            }
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(MusshErrKind::UnbalancedQuote(line.to_string()).into()),
+                    }
+                }
+            }
+            '\\' => match chars.next() {
+                // An escaped newline continues the line
+                Some('\n') | None => {}
+                Some(c) => word.get_or_insert_with(String::new).push(c),
+            },
+            c if c.is_whitespace() => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// Prefix the command line with exports of the variables, so they are set
+/// for the whole command line and not just its first command.
+crate fn export(env: &BTreeMap<String, String>, cmd: &str) -> MusshResult<String> {
+    let exports = env
+        .iter()
+        .map(|(name, value)| {
+            check_env_name(name)?;
+            Ok(format!("export {}={}; ", name, shell_quote(value)))
+        })
+        .collect::<MusshResult<String>>()?;
+    Ok(exports + cmd)
+}
+
+/// Is the name one a POSIX shell can export?
+crate fn check_env_name(name: &str) -> MusshResult<()> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .map_or(false, |first| first == '_' || first.is_ascii_alphabetic())
+        && chars.all(|c| c == '_' || c.is_ascii_alphanumeric());
+    if valid {
+        Ok(())
+    } else {
+        Err(MusshErrKind::InvalidEnv(name.to_string()).into())
+    }
+}
+
+/// Prefix the command line with a change to the working directory.  A
+/// leading `~` is left unquoted, so the shell expands it.
+crate fn in_dir(cwd: Option<&String>, cmd: &str) -> String {
+    match cwd {
+        Some(cwd) if cwd == "~" => format!("cd ~ && {}", cmd),
+        Some(cwd) => match cwd.strip_prefix("~/") {
+            Some(rest) => format!("cd ~/{} && {}", shell_quote(rest), cmd),
+            None => format!("cd {} && {}", shell_quote(cwd), cmd),
+        },
+        None => cmd.to_string(),
+    }
+}
+
 crate fn convert_duration(duration: &Duration) -> String {
     let seconds = duration.as_secs();
     let millis = duration.subsec_millis();
@@ -92,8 +419,16 @@ crate fn convert_duration(duration: &Duration) -> String {
 
 #[cfg(test)]
 mod test {
-    use super::as_set;
-    use indexmap::IndexSet;
+    use super::{
+        as_set, base64, check_env_name, expand_path, export, fnv1a, from_command_lines,
+        ifconfig_addresses, in_dir, interface_addresses, is_own_host, quote_words, shell_quote,
+        split_words, CmdType,
+    };
+    use crate::config::Host;
+    use indexmap::{IndexMap, IndexSet};
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::path::PathBuf;
 
     #[test]
     fn nums_as_set() {
@@ -108,4 +443,156 @@ mod test {
         let actual = vec!["one", "three", "three", "two", "one", "two", "two"];
         assert_eq!(as_set(actual), expected);
     }
+
+    #[test]
+    fn expand_paths() {
+        let home = env::var("HOME").unwrap_or_default();
+        env::set_var("MUSSH_TEST_KEY_DIR", "/etc/keys");
+        assert_eq!(expand_path("~"), PathBuf::from(&home));
+        assert_eq!(
+            expand_path("~/.ssh/id_rsa"),
+            PathBuf::from(format!("{}/.ssh/id_rsa", home))
+        );
+        assert_eq!(
+            expand_path("$MUSSH_TEST_KEY_DIR/id_rsa"),
+            PathBuf::from("/etc/keys/id_rsa")
+        );
+        assert_eq!(
+            expand_path("${MUSSH_TEST_KEY_DIR}_old/id_rsa"),
+            PathBuf::from("/etc/keys_old/id_rsa")
+        );
+        assert_eq!(
+            expand_path("$MUSSH_TEST_UNSET/id_rsa"),
+            PathBuf::from("$MUSSH_TEST_UNSET/id_rsa")
+        );
+        assert_eq!(expand_path("~other/id_rsa"), PathBuf::from("~other/id_rsa"));
+    }
+
+    #[test]
+    fn base64_unpadded() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg");
+        assert_eq!(base64(b"fo"), "Zm8");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xfb, 0xff]), "+/8");
+    }
+
+    #[test]
+    fn fnv1a_hashes() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn shell_quotes() {
+        assert_eq!(shell_quote("/etc/motd"), "'/etc/motd'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    #[test]
+    fn env_prefixes() {
+        let mut env = BTreeMap::new();
+        assert_eq!(export(&env, "make").ok(), Some("make".to_string()));
+        let _ = env.insert("RUST_LOG".to_string(), "debug".to_string());
+        let _ = env.insert("GREETING".to_string(), "it's me".to_string());
+        assert_eq!(
+            export(&env, "cargo run && cargo test").ok(),
+            Some(
+                "export GREETING='it'\\''s me'; export RUST_LOG='debug'; cargo run && cargo test"
+                    .to_string()
+            )
+        );
+        let _ = env.insert("BAD NAME".to_string(), String::new());
+        assert!(export(&env, "make").is_err());
+        assert!(check_env_name("_PATH2").is_ok());
+        assert!(check_env_name("2PATH").is_err());
+        assert!(check_env_name("").is_err());
+        assert_eq!(
+            in_dir(Some(&"/srv/app".to_string()), "make"),
+            "cd '/srv/app' && make"
+        );
+        assert_eq!(
+            in_dir(Some(&"~/my app".to_string()), "make"),
+            "cd ~/'my app' && make"
+        );
+        assert_eq!(in_dir(None, "make"), "make");
+    }
+
+    #[test]
+    fn words() {
+        let split = |line: &str| split_words(line).unwrap_or_default();
+        assert_eq!(split("  ls   -la /tmp "), vec!["ls", "-la", "/tmp"]);
+        assert_eq!(
+            split(r#"echo 'it'\''s' "a \"b\" \$HOME" c\ d e''"#),
+            vec!["echo", "it's", r#"a "b" $HOME"#, "c d", "e"]
+        );
+        assert_eq!(split("printf '' x"), vec!["printf", "", "x"]);
+        assert_eq!(split(r#"echo "\n""#), vec!["echo", r"\n"]);
+        assert!(split_words("echo 'oops").is_err());
+        assert!(split_words("echo \"oops").is_err());
+
+        let words = vec!["grep", "-e", "it's", "$HOME", ""];
+        assert_eq!(quote_words(&words), r"'grep' '-e' 'it'\''s' '$HOME' ''");
+        assert_eq!(split(&quote_words(&words)), words);
+    }
+
+    #[test]
+    fn own_hosts() {
+        for hostname in &[
+            "localhost",
+            "LOCALHOST.",
+            "127.0.0.1",
+            "127.1.2.3",
+            "::1",
+            "[::1]",
+        ] {
+            assert!(is_own_host(hostname), "{}", hostname);
+        }
+        assert!(!is_own_host("192.0.2.1"));
+        assert!(!is_own_host("mussh.invalid"));
+        for address in interface_addresses() {
+            assert!(is_own_host(&address.to_string()), "{}", address);
+        }
+
+        if let Ok(output) = std::process::Command::new("hostname").output() {
+            let hostname = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if !hostname.is_empty() {
+                assert!(is_own_host(&hostname.to_uppercase()));
+            }
+        }
+    }
+
+    #[test]
+    fn ifconfig_output() {
+        let linux = "eth0: flags=4163<UP,BROADCAST,RUNNING,MULTICAST>  mtu 1500
+        inet 10.0.0.3  netmask 255.255.255.0  broadcast 10.0.0.255
+        inet6 fe80::1%eth0  prefixlen 64  scopeid 0x20<link>
+eth1      Link encap:Ethernet
+          inet addr:10.0.1.3  Bcast:10.0.1.255  Mask:255.255.255.0
+          inet6 addr: 2001:db8::3/64 Scope:Global";
+        let addresses: Vec<String> = ifconfig_addresses(linux)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            addresses,
+            vec!["10.0.0.3", "fe80::1", "10.0.1.3", "2001:db8::3"]
+        );
+    }
+
+    #[test]
+    fn command_lines() {
+        let mut cmds = IndexMap::new();
+        let _ = cmds.insert("ls".to_string(), "ls -al".to_string());
+        let mut cmd_types = IndexMap::new();
+        let _ = cmd_types.insert(CmdType::Cmd, cmds);
+        let mut map = IndexMap::new();
+        let _ = map.insert("m1".to_string(), (Host::default(), cmd_types));
+
+        let converted = from_command_lines(map);
+        assert_eq!(converted["m1"].1[&CmdType::Cmd]["ls"].command(), "ls -al");
+    }
 }